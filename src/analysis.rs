@@ -0,0 +1,292 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::assembler::CompilerWarning;
+use crate::ast::*;
+
+/// Variables assigned on every path (`definitely`) and on at least one path (`possibly`) to a point in the program
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct InitState {
+    definitely: HashSet<String>,
+    possibly: HashSet<String>,
+}
+
+impl InitState {
+    fn assign(&mut self, name: &str) {
+        self.definitely.insert(name.to_string());
+        self.possibly.insert(name.to_string());
+    }
+    /// State where two paths of control flow meet
+    fn join(&self, other: &InitState) -> InitState {
+        InitState {
+            definitely: self.definitely.intersection(&other.definitely).cloned().collect(),
+            possibly: self.possibly.union(&other.possibly).cloned().collect(),
+        }
+    }
+}
+
+/// What a procedure does with its parameters, as seen from a call site
+#[derive(Debug, Clone)]
+struct ProcedureSummary {
+    parameters: Vec<String>,
+    /// Parameters that may be read before the procedure assigns them, so the caller has to initialise them
+    reads: HashSet<String>,
+    definitely_assigns: HashSet<String>,
+    possibly_assigns: HashSet<String>,
+}
+
+/// Definite-assignment analysis of one scope, that is main or a single procedure
+struct ScopeAnalysis<'a> {
+    summaries: &'a HashMap<String, ProcedureSummary>,
+    declared: HashSet<String>,
+    arrays: HashSet<String>,
+    parameters: HashSet<String>,
+    reads: HashSet<String>,
+    warnings: Vec<CompilerWarning>,
+    /// Off while iterating loops to a fixed point, so that every use gets reported once, with the final state
+    reporting: bool,
+}
+
+impl<'a> ScopeAnalysis<'a> {
+    fn new(
+        summaries: &'a HashMap<String, ProcedureSummary>,
+        declared: HashSet<String>,
+        arrays: HashSet<String>,
+        parameters: HashSet<String>,
+    ) -> ScopeAnalysis<'a> {
+        ScopeAnalysis {
+            summaries,
+            declared,
+            arrays,
+            parameters,
+            reads: HashSet::new(),
+            warnings: vec![],
+            reporting: true,
+        }
+    }
+    fn analyse_commands(&mut self, commands: &Commands, mut state: InitState) -> InitState {
+        for command in commands {
+            state = self.analyse_command(command, state);
+        }
+        state
+    }
+    fn analyse_command(&mut self, command: &Command, mut state: InitState) -> InitState {
+        match command {
+            Command::Assign(identifier, expression, _) => {
+                self.use_expression(expression, &state);
+                self.use_index(identifier, &state);
+                state.assign(identifier_name(identifier));
+                state
+            },
+            Command::If(condition, commands, else_commands, _) => {
+                self.use_condition(condition, &state);
+                let then_state = self.analyse_commands(commands, state.clone());
+                let else_state = match else_commands {
+                    Some(else_commands) => self.analyse_commands(else_commands, state),
+                    None => state,
+                };
+                then_state.join(&else_state)
+            },
+            Command::While(condition, commands, _) => {
+                let head = self.loop_head(&state, |analysis, head| {
+                    analysis.use_condition(condition, head);
+                    analysis.analyse_commands(commands, head.clone())
+                });
+                self.use_condition(condition, &head);
+                self.analyse_commands(commands, head.clone());
+                // The loop is left only when the condition fails at its head
+                head
+            },
+            Command::Repeat(commands, condition, _) => {
+                let head = self.loop_head(&state, |analysis, head| {
+                    let body_state = analysis.analyse_commands(commands, head.clone());
+                    analysis.use_condition(condition, &body_state);
+                    body_state
+                });
+                let body_state = self.analyse_commands(commands, head);
+                self.use_condition(condition, &body_state);
+                body_state
+            },
+            Command::ProcCall((procedure_id, arguments), _) => {
+                let summary = self
+                    .summaries
+                    .get(&procedure_id.0)
+                    .filter(|summary| summary.parameters.len() == arguments.len())
+                    .cloned();
+                match summary {
+                    Some(summary) => {
+                        for (argument, parameter) in arguments.iter().zip(&summary.parameters) {
+                            if summary.reads.contains(parameter) {
+                                self.use_variable(argument, &state);
+                            }
+                        }
+                        for (argument, parameter) in arguments.iter().zip(&summary.parameters) {
+                            if summary.definitely_assigns.contains(parameter) {
+                                state.assign(&argument.0);
+                            } else if summary.possibly_assigns.contains(parameter) {
+                                state.possibly.insert(argument.0.clone());
+                            }
+                        }
+                    },
+                    // Unknown procedures are reported elsewhere, assume they initialise everything they get
+                    None => {
+                        for argument in arguments {
+                            state.assign(&argument.0);
+                        }
+                    },
+                }
+                state
+            },
+            Command::Read(identifier, _) => {
+                self.use_index(identifier, &state);
+                state.assign(identifier_name(identifier));
+                state
+            },
+            Command::Write(value, _) => {
+                self.use_value(value, &state);
+                state
+            },
+        }
+    }
+    /// Iterates the loop until the state at its head stops changing.
+    /// `body` gets the state at the head and returns the state flowing back to it.
+    fn loop_head(&mut self, entry: &InitState, body: impl Fn(&mut ScopeAnalysis<'a>, &InitState) -> InitState) -> InitState {
+        let reporting = self.reporting;
+        self.reporting = false;
+        let mut head = entry.clone();
+        loop {
+            let back_edge = body(self, &head);
+            let next_head = entry.join(&back_edge);
+            if next_head == head {
+                break;
+            }
+            head = next_head;
+        }
+        self.reporting = reporting;
+        head
+    }
+    fn use_condition(&mut self, condition: &Condition, state: &InitState) {
+        match condition {
+            Condition::Equal(value_0, value_1, _)
+            | Condition::NotEqual(value_0, value_1, _)
+            | Condition::Greater(value_0, value_1, _)
+            | Condition::Lower(value_0, value_1, _)
+            | Condition::GreaterOrEqual(value_0, value_1, _)
+            | Condition::LowerOrEqual(value_0, value_1, _) => {
+                self.use_value(value_0, state);
+                self.use_value(value_1, state);
+            },
+        }
+    }
+    fn use_expression(&mut self, expression: &Expression, state: &InitState) {
+        match expression {
+            Expression::Val(value, _) => self.use_value(value, state),
+            Expression::Add(value_0, value_1, _)
+            | Expression::Substract(value_0, value_1, _)
+            | Expression::Multiply(value_0, value_1, _)
+            | Expression::Divide(value_0, value_1, _)
+            | Expression::Modulo(value_0, value_1, _) => {
+                self.use_value(value_0, state);
+                self.use_value(value_1, state);
+            },
+        }
+    }
+    fn use_value(&mut self, value: &Value, state: &InitState) {
+        if let Value::Id(identifier) = value {
+            match identifier {
+                Identifier::Base(id) | Identifier::NumIndexed(id, _, _) => self.use_variable(id, state),
+                Identifier::PidIndexed(id, index_id, _) => {
+                    self.use_variable(id, state);
+                    self.use_variable(index_id, state);
+                },
+            }
+        }
+    }
+    /// The index of an array element being assigned is read
+    fn use_index(&mut self, identifier: &Identifier, state: &InitState) {
+        if let Identifier::PidIndexed(_, index_id, _) = identifier {
+            self.use_variable(index_id, state);
+        }
+    }
+    fn use_variable(&mut self, id: &Pidentifier, state: &InitState) {
+        if !self.declared.contains(&id.0) || state.definitely.contains(&id.0) {
+            return;
+        }
+        // Arrays are assigned element by element, one assignment on some path is taken as enough
+        if self.arrays.contains(&id.0) && state.possibly.contains(&id.0) {
+            return;
+        }
+        if self.parameters.contains(&id.0) {
+            self.reads.insert(id.0.clone());
+            return;
+        }
+        if !self.reporting {
+            return;
+        }
+        if state.possibly.contains(&id.0) {
+            self.warnings.push(CompilerWarning::PossiblyUsedBeforeInitialisation(id.0.clone(), id.1));
+        } else {
+            self.warnings.push(CompilerWarning::UsedBeforeInitialisation(id.0.clone(), id.1));
+        }
+    }
+}
+
+fn identifier_name(identifier: &Identifier) -> &str {
+    match identifier {
+        Identifier::Base(id) => &id.0,
+        Identifier::NumIndexed(id, _, _) => &id.0,
+        Identifier::PidIndexed(id, _, _) => &id.0,
+    }
+}
+
+fn declared_names(declarations: &Option<Declarations>) -> impl Iterator<Item = String> + '_ {
+    declarations.iter().flatten().map(|declaration| match declaration {
+        DeclarationVariant::Base(id) => id.0.clone(),
+        DeclarationVariant::NumIndexed(id, _, _) => id.0.clone(),
+    })
+}
+
+fn declared_arrays(declarations: &Option<Declarations>) -> impl Iterator<Item = String> + '_ {
+    declarations.iter().flatten().filter_map(|declaration| match declaration {
+        DeclarationVariant::Base(_) => None,
+        DeclarationVariant::NumIndexed(id, _, _) => Some(id.0.clone()),
+    })
+}
+
+/// Flow-sensitive check that every variable is assigned before it is read, through IF joins and loop back-edges.
+/// Procedures are summarised in declaration order, so a call knows which arguments it reads and which it assigns.
+pub fn check_initialisation(program: &Program) -> Vec<CompilerWarning> {
+    let mut warnings: Vec<CompilerWarning> = Vec::new();
+    let mut summaries: HashMap<String, ProcedureSummary> = HashMap::new();
+    for procedure in program.0.iter().flatten() {
+        let ((name, _), declared_arguments, _) = &procedure.0;
+        let parameters: Vec<String> = declared_arguments
+            .iter()
+            .map(|argument| match argument {
+                ArgumentsDeclarationVariant::Base(id) => id.0.clone(),
+                ArgumentsDeclarationVariant::Table(id, _) => id.0.clone(),
+            })
+            .collect();
+        let declared: HashSet<String> = parameters.iter().cloned().chain(declared_names(&procedure.1)).collect();
+        let table_parameters = declared_arguments.iter().filter_map(|argument| match argument {
+            ArgumentsDeclarationVariant::Base(_) => None,
+            ArgumentsDeclarationVariant::Table(id, _) => Some(id.0.clone()),
+        });
+        let arrays: HashSet<String> = table_parameters.chain(declared_arrays(&procedure.1)).collect();
+        let mut analysis = ScopeAnalysis::new(&summaries, declared, arrays, parameters.iter().cloned().collect());
+        let exit = analysis.analyse_commands(&procedure.2, InitState::default());
+        let summary = ProcedureSummary {
+            reads: analysis.reads,
+            definitely_assigns: parameters.iter().filter(|p| exit.definitely.contains(*p)).cloned().collect(),
+            possibly_assigns: parameters.iter().filter(|p| exit.possibly.contains(*p)).cloned().collect(),
+            parameters,
+        };
+        warnings.extend(analysis.warnings);
+        // A repeated declaration is an error reported elsewhere, the first one is kept
+        summaries.entry(name.clone()).or_insert(summary);
+    }
+    let main = &program.1;
+    let mut analysis = ScopeAnalysis::new(&summaries, declared_names(&main.0).collect(), declared_arrays(&main.0).collect(), HashSet::new());
+    analysis.analyse_commands(&main.1, InitState::default());
+    warnings.extend(analysis.warnings);
+    warnings
+}
@@ -1,45 +1,146 @@
 use std::{
-    collections::{HashMap, HashSet, VecDeque}, fmt::Display
+    collections::{HashMap, HashSet}, fmt::Display
 };
 
 use crate::ast::*;
+use crate::ir::*;
+use crate::OptLevel;
+
+use num_traits::{ToPrimitive, Zero};
 
 use Register::*;
 
-#[derive(Debug, Clone)]
+/// Uses of `Mul`, `Div` or `Mod` from which they share one routine when optimising for speed, each call costs 5 more
+const SHARED_ROUTINE_USES: usize = 8;
+
+/// Registers that can hold variables, `G` first as only calls clobber it
+const VARIABLE_REGISTERS: [Register; 4] = [G, F, D, E];
+
+/// A use inside a loop counts as this many uses outside of it when choosing variables for registers
+const LOOP_WEIGHT: u64 = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum CompilerError {
-    UndeclaredVariable(String, usize),
-    UndeclaredProcedure(String, usize),
-    IncorrectUseOfVariable(String, usize),
-    IndexOutOfBounds(String, usize),
-    ArrayUsedAsIndex(String, usize),
-    WrongArgumentType(String, usize),
-    DuplicateVariableDeclaration(String, usize),
-    DuplicateProcedureDeclaration(String, usize),
-    RecursiveProcedureCall(String, usize),
-    WrongNumberOfArguments(String, usize),
+    UndeclaredVariable(String, Span),
+    UndeclaredProcedure(String, Span),
+    IncorrectUseOfVariable(String, Span),
+    IndexOutOfBounds(String, Span),
+    ArrayUsedAsIndex(String, Span),
+    /// Parameter name, the argument passed for it and the parameter's declaration
+    WrongArgumentType(String, Span, Span),
+    /// The repeated declaration and the first one
+    DuplicateVariableDeclaration(String, Span, Span),
+    /// The repeated declaration and the first one
+    DuplicateProcedureDeclaration(String, Span, Span),
+    /// The chain of calls forming the cycle, starting and ending with the same procedure, and the call closing it
+    RecursiveProcedureCall(Vec<String>, Span),
+    /// The call and the callee's declaration
+    ProcedureCalledBeforeDeclaration(String, Span, Span),
+    /// The call, the number of declared parameters and the procedure head
+    WrongNumberOfArguments(String, Span, usize, Span),
+    /// The array's declaration, with a size past the end of the memory
+    ArrayTooLarge(String, Span),
+    /// A variable declared after the memory is already used up
+    OutOfMemory(String, Span),
+    /// What is wrong with the command line
+    InvalidCommandLine(String),
+    /// Path and the reason reported by the system
+    UnreadableFile(String, String),
+    /// Path and the reason reported by the system
+    UnwritableFile(String, String),
+    /// `fmt --check` found the file not in canonical style, with its path
+    NotFormatted(String),
+    /// The formatted program did not parse back to the same AST
+    FormattingChangedProgram,
 }
 
 impl CompilerError {
-    pub fn get_byte(&self) -> usize {
+    /// `None` for errors that are not about the source, like a missing input file
+    pub fn get_span(&self) -> Option<Span> {
         match self {
-            CompilerError::UndeclaredVariable(_, line) => *line,
-            CompilerError::UndeclaredProcedure(_, line) => *line,
-            CompilerError::IncorrectUseOfVariable(_, line) => *line,
-            CompilerError::IndexOutOfBounds(_, line) => *line,
-            CompilerError::ArrayUsedAsIndex(_, line) => *line,
-            CompilerError::WrongArgumentType(_, line) => *line,
-            CompilerError::DuplicateVariableDeclaration(_, line) => *line,
-            CompilerError::DuplicateProcedureDeclaration(_, line) => *line,
-            CompilerError::RecursiveProcedureCall(_, line) => *line,
-            CompilerError::WrongNumberOfArguments(_, line) => *line,
+            CompilerError::UndeclaredVariable(_, span) => Some(*span),
+            CompilerError::UndeclaredProcedure(_, span) => Some(*span),
+            CompilerError::IncorrectUseOfVariable(_, span) => Some(*span),
+            CompilerError::IndexOutOfBounds(_, span) => Some(*span),
+            CompilerError::ArrayUsedAsIndex(_, span) => Some(*span),
+            CompilerError::WrongArgumentType(_, span, _) => Some(*span),
+            CompilerError::DuplicateVariableDeclaration(_, span, _) => Some(*span),
+            CompilerError::DuplicateProcedureDeclaration(_, span, _) => Some(*span),
+            CompilerError::RecursiveProcedureCall(_, span) => Some(*span),
+            CompilerError::ProcedureCalledBeforeDeclaration(_, span, _) => Some(*span),
+            CompilerError::WrongNumberOfArguments(_, span, _, _) => Some(*span),
+            CompilerError::ArrayTooLarge(_, span) => Some(*span),
+            CompilerError::OutOfMemory(_, span) => Some(*span),
+            CompilerError::InvalidCommandLine(_) => None,
+            CompilerError::UnreadableFile(_, _) => None,
+            CompilerError::UnwritableFile(_, _) => None,
+            CompilerError::NotFormatted(_) => None,
+            CompilerError::FormattingChangedProgram => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompilerWarning {
+    UsedBeforeInitialisation(String, Span),
+    /// Assigned on some paths to the use but not on all of them
+    PossiblyUsedBeforeInitialisation(String, Span),
+    UnusedVariable(String, Span),
+    UnusedProcedure(String, Span),
+}
+
+/// Groups of warnings that can be enabled, disabled or turned into errors together
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WarningCategory {
+    Uninitialised,
+    MaybeUninitialised,
+    UnusedVariable,
+    UnusedProcedure,
+}
+
+impl WarningCategory {
+    pub const ALL: [WarningCategory; 4] = [
+        WarningCategory::Uninitialised,
+        WarningCategory::MaybeUninitialised,
+        WarningCategory::UnusedVariable,
+        WarningCategory::UnusedProcedure,
+    ];
+    /// Name used by the `-W` flags
+    pub fn name(&self) -> &'static str {
+        match self {
+            WarningCategory::Uninitialised => "uninitialised",
+            WarningCategory::MaybeUninitialised => "maybe-uninitialised",
+            WarningCategory::UnusedVariable => "unused-variable",
+            WarningCategory::UnusedProcedure => "unused-procedure",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<WarningCategory> {
+        WarningCategory::ALL.into_iter().find(|category| category.name() == name)
+    }
+}
+
+impl CompilerWarning {
+    pub fn get_span(&self) -> Span {
+        match self {
+            CompilerWarning::UsedBeforeInitialisation(_, span) => *span,
+            CompilerWarning::PossiblyUsedBeforeInitialisation(_, span) => *span,
+            CompilerWarning::UnusedVariable(_, span) => *span,
+            CompilerWarning::UnusedProcedure(_, span) => *span,
+        }
+    }
+    pub fn category(&self) -> WarningCategory {
+        match self {
+            CompilerWarning::UsedBeforeInitialisation(_, _) => WarningCategory::Uninitialised,
+            CompilerWarning::PossiblyUsedBeforeInitialisation(_, _) => WarningCategory::MaybeUninitialised,
+            CompilerWarning::UnusedVariable(_, _) => WarningCategory::UnusedVariable,
+            CompilerWarning::UnusedProcedure(_, _) => WarningCategory::UnusedProcedure,
         }
     }
 }
 
 #[allow(dead_code)]
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-enum Register {
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum Register {
     A,
     B,
     C,
@@ -65,10 +166,20 @@ impl Display for Register {
     }
 }
 
+/// A place in the pseudo-assembly, given its address when the program is laid out
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct Label(pub usize);
+
+impl Display for Label {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "L{}", self.0)
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
-/// Jumps are relative in our pseudo-Instructions
-enum Instruction {
+/// Jumps go to labels in our pseudo-Instructions
+pub enum Instruction {
     Read,
     Write,
     Load(Register),
@@ -82,941 +193,962 @@ enum Instruction {
     Dec(Register),
     Shl(Register),
     Shr(Register),
-    Jump(i64),
-    Jpos(i64),
-    Jzero(i64),
-    Strk,
-    Jumpr,
+    Jump(Label),
+    Jpos(Label),
+    Jzero(Label),
+    Strk(Register),
+    Jumpr(Register),
     Halt,
     Mul,
     Div,
     Mod,
+    /// Marks the instruction after it, takes no room
+    Label(Label),
+}
+
+/// Pseudo-assembly as text, jumps go to labels and macro instructions are not expanded
+impl Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Instruction::Read => write!(f, "READ"),
+            Instruction::Write => write!(f, "WRITE"),
+            Instruction::Load(register) => write!(f, "LOAD {register}"),
+            Instruction::Store(register) => write!(f, "STORE {register}"),
+            Instruction::Add(register) => write!(f, "ADD {register}"),
+            Instruction::Sub(register) => write!(f, "SUB {register}"),
+            Instruction::Get(register) => write!(f, "GET {register}"),
+            Instruction::Put(register) => write!(f, "PUT {register}"),
+            Instruction::Rst(register) => write!(f, "RST {register}"),
+            Instruction::Inc(register) => write!(f, "INC {register}"),
+            Instruction::Dec(register) => write!(f, "DEC {register}"),
+            Instruction::Shl(register) => write!(f, "SHL {register}"),
+            Instruction::Shr(register) => write!(f, "SHR {register}"),
+            Instruction::Jump(label) => write!(f, "JUMP {label}"),
+            Instruction::Jpos(label) => write!(f, "JPOS {label}"),
+            Instruction::Jzero(label) => write!(f, "JZERO {label}"),
+            Instruction::Strk(register) => write!(f, "STRK {register}"),
+            Instruction::Jumpr(register) => write!(f, "JUMPR {register}"),
+            Instruction::Halt => write!(f, "HALT"),
+            Instruction::Mul => write!(f, "MUL"),
+            Instruction::Div => write!(f, "DIV"),
+            Instruction::Mod => write!(f, "MOD"),
+            Instruction::Label(label) => write!(f, "{label}:"),
+        }
+    }
 }
 
 impl Instruction {
-    fn len(&self) -> u64 {
+    /// What the virtual machine charges for the instruction. A macro instruction costs one pass over each of its instructions.
+    pub fn cost(&self) -> u64 {
         match self {
-            Instruction::Mul => 18,
-            Instruction::Div => 23,
-            Instruction::Mod => 24,
+            Instruction::Read | Instruction::Write => 100,
+            Instruction::Load(_) | Instruction::Store(_) => 50,
+            Instruction::Add(_) | Instruction::Sub(_) => 5,
+            Instruction::Halt | Instruction::Label(_) => 0,
+            Instruction::Mul | Instruction::Div | Instruction::Mod => expand(*self, &mut 0).iter().map(Instruction::cost).sum(),
+            _ => 1,
+        }
+    }
+    /// Length once macro instructions are expanded
+    pub(crate) fn len(&self) -> u64 {
+        match self {
+            Instruction::Label(_) => 0,
+            Instruction::Mul | Instruction::Div | Instruction::Mod => expand(*self, &mut 0).iter().map(Instruction::len).sum(),
             _ => 1,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum VariableVariant {
     Atomic(u64),
-    Table(u64, u64),
+    Table(u64),
+    /// Parameter of a subroutine, the cell holds the address of the argument
+    Reference(u64),
+    /// Array parameter of a subroutine, the cell holds the start of the argument
+    TableReference(u64),
+    /// Scalar kept in a register, with the cell it is spilled to
+    Register(Register, u64),
 }
 
+/// Static cost of the code built for a source command, every instruction counted once
 #[derive(Debug, Clone)]
-struct ProcedureBuilder {
-    name: String,
-    declared_arguments: Vec<ArgumentsDeclarationVariant>,
-    declarations: Option<Declarations>,
-    commands: Commands,
-}
-
-impl ProcedureBuilder {
-    pub fn new(procedure: Procedure) -> ProcedureBuilder {
-        let mut pb = ProcedureBuilder {
-            name: procedure.0.0.0,
-            declared_arguments: procedure.0 .1,
-            declarations: procedure.1,
-            commands: procedure.2,
-        };
-        pb.rename_commands();
-        pb
-    }
-    fn rename_commands(&mut self){
-        let new_commands: Vec<Command> = self.commands
-            .iter()
-            .cloned()
-            .map(|com| self.rename_command(com))
-            .collect();
-        self.commands = new_commands;
-    }
-    fn rename_command(&self, command: Command) -> Command {
-        match command {
-            Command::Assign(id, expression) => {
-                let new_id = self.rename_indentifier(id);
-                let new_expression = match expression {
-                    Expression::Val(value) => {
-                        let new_value = self.rename_value(value);
-                        Expression::Val(new_value)
-                    }
-                    Expression::Add(value0, value1) => {
-                        let new_value0 = self.rename_value(value0);
-                        let new_value1 = self.rename_value(value1);
-                        Expression::Add(new_value0, new_value1)
-                    }
-                    Expression::Substract(value0, value1) => {
-                        let new_value0 = self.rename_value(value0);
-                        let new_value1 = self.rename_value(value1);
-                        Expression::Substract(new_value0, new_value1)
-                    }
-                    Expression::Multiply(value0, value1) => {
-                        let new_value0 = self.rename_value(value0);
-                        let new_value1 = self.rename_value(value1);
-                        Expression::Multiply(new_value0, new_value1)
-                    }
-                    Expression::Divide(value0, value1) => {
-                        let new_value0 = self.rename_value(value0);
-                        let new_value1 = self.rename_value(value1);
-                        Expression::Divide(new_value0, new_value1)
-                    }
-                    Expression::Modulo(value0, value1) => {
-                        let new_value0 = self.rename_value(value0);
-                        let new_value1 = self.rename_value(value1);
-                        Expression::Modulo(new_value0, new_value1)
+pub struct CommandCost {
+    pub span: Span,
+    /// Commands it is nested in
+    pub depth: usize,
+    /// Loops it is nested in
+    pub loops: usize,
+    pub cost: u64,
+}
+
+/// Static cost of main or of a procedure compiled as a subroutine, with the commands of its body
+#[derive(Debug, Clone)]
+pub struct BodyCost {
+    /// `None` for main
+    pub procedure: Option<String>,
+    /// The whole body, getting in and out of a subroutine included
+    pub cost: u64,
+    pub commands: Vec<CommandCost>,
+}
+
+/// Static costs of the program, before it runs
+#[derive(Debug, Clone, Default)]
+pub struct CostReport {
+    pub bodies: Vec<BodyCost>,
+    /// Procedures copied into their calls, their costs are part of the calls
+    pub inlined: Vec<String>,
+    /// Every instruction of the program counted once
+    pub total: u64,
+}
+
+/// A procedure compiled once and called with `STRK`/`JUMPR`
+#[derive(Debug, Clone)]
+struct Subroutine {
+    /// One per parameter, each holding the address of its argument
+    parameter_slots: Vec<u64>,
+    /// Marks the first instruction
+    entry: Label,
+}
+
+/// The code of main or of a subroutine
+#[derive(Debug, Clone)]
+struct Body {
+    /// `None` for main
+    procedure: Option<String>,
+    instructions: Vec<Instruction>,
+    /// The commands of the source, each with the instructions built for it alone, before any rewriting
+    commands: Vec<(SourceCommand, Vec<Instruction>)>,
+}
+
+/// The pseudo-assembly of a module: main, and every procedure main still calls compiled once as a subroutine
+#[derive(Debug, Clone)]
+pub struct Assembler {
+    /// The subroutines in declaration order, then main
+    bodies: Vec<Body>,
+    /// Procedures without a body of their own, as they are copied into every call
+    inlined: Vec<String>,
+    /// Of `Mul`, `Div` and `Mod`, the ones called as one routine after the end of the program
+    shared_routines: Vec<Instruction>,
+    /// Number of the next label to be made
+    next_label: usize,
+}
+
+impl Assembler {
+    /// Builds the code of every function of `module` reached from main, as the IR is after the passes.
+    /// `used` are the procedures main called before the passes, the ones no longer called were inlined.
+    pub fn new(module: &Module, used: &HashSet<String>) -> Assembler {
+        let called = called_procedures(module);
+        let mut next_label = 0;
+        let subroutines = subroutines(module, |name| called.contains(name), &mut next_label);
+        let mut bodies: Vec<Body> = Vec::new();
+        let mut inlined: Vec<String> = Vec::new();
+        for function in &module.functions {
+            match &function.name {
+                Some(name) if !called.contains(name) => {
+                    if used.contains(name) {
+                        inlined.push(name.clone());
                     }
-                };
-                Command::Assign(new_id, new_expression)
+                },
+                _ => bodies.push(Generator::new(function, &subroutines, &mut next_label).body()),
             }
-            Command::If(condition, commands, else_commands) => {
-                let new_condition = self.rename_condition(condition);
-                let new_commands: Vec<Command> = commands
-                    .iter()
-                    .cloned()
-                    .map(|com| self.rename_command(com))
-                    .collect();
-                let new_else_condition: Option<Vec<Command>> = else_commands.map(|else_commands| else_commands
-                            .iter()
-                            .cloned()
-                            .map(|com| self.rename_command(com))
-                            .collect());
-                Command::If(new_condition, new_commands, new_else_condition)
+        }
+        Assembler { bodies, inlined, shared_routines: Vec::new(), next_label }
+    }
+    /// The program: a jump over the subroutines if there are any, the subroutines, main and the shared routines.
+    /// Macro instructions are not expanded and jumps go to labels.
+    pub fn pseudo_assembly(&self) -> Vec<Instruction> {
+        let mut pseudo_assembly: Vec<Instruction> = Vec::new();
+        if let Some((main, subroutines)) = self.bodies.split_last() {
+            if !subroutines.is_empty() {
+                let main_label = Label(self.next_label);
+                pseudo_assembly.push(Instruction::Jump(main_label));
+                pseudo_assembly.extend(subroutines.iter().flat_map(|body| body.instructions.iter().copied()));
+                pseudo_assembly.push(Instruction::Label(main_label));
             }
-            Command::While(condition, commands) => {
-                let new_condition = self.rename_condition(condition);
-                let new_commands: Vec<Command> = commands
-                    .iter()
-                    .cloned()
-                    .map(|com| self.rename_command(com))
-                    .collect();
-                Command::While(new_condition, new_commands)
+            pseudo_assembly.extend(main.instructions.iter().copied());
+        }
+        // The routines get their return address in `H`, which is free once the operands are in `B` and `C`
+        let entries: Vec<(Instruction, Label)> =
+            self.shared_routines.iter().enumerate().map(|(index, routine)| (*routine, Label(self.next_label + 1 + index))).collect();
+        let mut instructions: Vec<Instruction> = Vec::new();
+        for instruction in pseudo_assembly {
+            match entries.iter().find(|(routine, _)| *routine == instruction) {
+                Some((_, entry)) => instructions.extend([Instruction::Strk(H), Instruction::Jump(*entry)]),
+                None => instructions.push(instruction),
             }
-            Command::Repeat(commands, condition) => {
-                let new_condition = self.rename_condition(condition);
-                let new_commands: Vec<Command> = commands
+        }
+        for (routine, entry) in entries {
+            instructions.extend([Instruction::Label(entry), routine, Instruction::Inc(H), Instruction::Inc(H), Instruction::Jumpr(H)]);
+        }
+        instructions
+    }
+    /// The final code
+    pub fn assemble(&self) -> String {
+        lay_out(&self.pseudo_assembly())
+    }
+    /// Static costs of the program. Commands are counted as they were built, bodies and the program as they are now.
+    pub fn cost_report(&self) -> CostReport {
+        let bodies = self
+            .bodies
+            .iter()
+            .map(|body| {
+                let mut costs: Vec<u64> = body.commands.iter().map(|(_, code)| static_cost(code, &self.shared_routines)).collect();
+                // Nested commands come after the command around them
+                for (index, (command, _)) in body.commands.iter().enumerate().rev() {
+                    if let Some(parent) = command.parent {
+                        costs[parent] += costs[index];
+                    }
+                }
+                let commands = body
+                    .commands
                     .iter()
-                    .cloned()
-                    .map(|com| self.rename_command(com))
+                    .zip(costs)
+                    .map(|((command, _), cost)| CommandCost { span: command.span, depth: command.depth, loops: command.loops, cost })
                     .collect();
-                Command::Repeat(new_commands, new_condition)
-            }
-            Command::ProcCall((name, arguments)) => {
-                let new_arguments: Vec<(String, usize)> = arguments.iter().map(|arg| (format!("{}@{}", arg.0, self.name), arg.1)).collect();
-                Command::ProcCall((name, new_arguments))
-            },
-            Command::Read(identifier) => {
-                let new_identifier = self.rename_indentifier(identifier);
-                Command::Read(new_identifier)
-            },
-            Command::Write(value) => {
-                let new_value = self.rename_value(value);
-                Command::Write(new_value)
-            },
-        }
+                BodyCost { procedure: body.procedure.clone(), cost: static_cost(&body.instructions, &self.shared_routines), commands }
+            })
+            .collect();
+        let total = self.pseudo_assembly().iter().map(Instruction::cost).sum();
+        CostReport { bodies, inlined: self.inlined.clone(), total }
     }
-    fn rename_condition(&self, condition: Condition) -> Condition {
-        match condition {
-            Condition::Equal(value0, value1) => {
-                let new_value0 = self.rename_value(value0);
-                let new_value1 = self.rename_value(value1);
-                Condition::Equal(new_value0, new_value1)
-            }
-            Condition::NotEqual(value0, value1) => {
-                let new_value0 = self.rename_value(value0);
-                let new_value1 = self.rename_value(value1);
-                Condition::NotEqual(new_value0, new_value1)
-            }
-            Condition::Greater(value0, value1) => {
-                let new_value0 = self.rename_value(value0);
-                let new_value1 = self.rename_value(value1);
-                Condition::Greater(new_value0, new_value1)
-            }
-            Condition::Lower(value0, value1) => {
-                let new_value0 = self.rename_value(value0);
-                let new_value1 = self.rename_value(value1);
-                Condition::Lower(new_value0, new_value1)
-            }
-            Condition::GreaterOrEqual(value0, value1) => {
-                let new_value0 = self.rename_value(value0);
-                let new_value1 = self.rename_value(value1);
-                Condition::GreaterOrEqual(new_value0, new_value1)
-            }
-            Condition::LowerOrEqual(value0, value1) => {
-                let new_value0 = self.rename_value(value0);
-                let new_value1 = self.rename_value(value1);
-                Condition::LowerOrEqual(new_value0, new_value1)
+    /// The code of main and of every subroutine, for the passes rewriting it. Jumps stay within a body, calls go through `JUMPR`.
+    pub(crate) fn bodies_mut(&mut self) -> impl Iterator<Item = &mut Vec<Instruction>> {
+        self.bodies.iter_mut().map(|body| &mut body.instructions)
+    }
+    /// Chooses which of `Mul`, `Div` and `Mod` are called instead of expanded, by their number of uses in the program:
+    /// two for `-Os`, where that is already shorter, and `SHARED_ROUTINE_USES` otherwise
+    pub(crate) fn share_routines(&mut self, opt_level: OptLevel) {
+        let least_uses = match opt_level {
+            OptLevel::Os => 2,
+            _ => SHARED_ROUTINE_USES,
+        };
+        let uses = |routine: &Instruction| self.bodies.iter().flat_map(|body| &body.instructions).filter(|instruction| *instruction == routine).count();
+        self.shared_routines = [Instruction::Mul, Instruction::Div, Instruction::Mod].into_iter().filter(|routine| uses(routine) >= least_uses).collect();
+    }
+}
+
+/// Procedures main calls, directly or through other procedures
+pub(crate) fn called_procedures(module: &Module) -> HashSet<String> {
+    let mut called: HashSet<String> = HashSet::new();
+    let mut pending: Vec<&Function> = module.functions.iter().filter(|function| function.name.is_none()).collect();
+    while let Some(function) = pending.pop() {
+        for operation in function.blocks.iter().flat_map(|block| &block.operations) {
+            if let Operation::Call(callee, _) = operation {
+                if called.insert(callee.clone()) {
+                    pending.extend(module.procedure(callee));
+                }
             }
         }
     }
-    fn rename_value(&self, value: Value) -> Value {
-        match value {
-            Value::Num(_) => value.clone(),
-            Value::Id(id) => Value::Id(self.rename_indentifier(id)),
+    called
+}
+
+/// The procedures of `module` that `compiled` picks, each with a label of its own
+fn subroutines(module: &Module, compiled: impl Fn(&str) -> bool, next_label: &mut usize) -> HashMap<String, Subroutine> {
+    let mut subroutines: HashMap<String, Subroutine> = HashMap::new();
+    for function in &module.functions {
+        let Some(name) = function.name.as_ref().filter(|name| compiled(name)) else {
+            continue;
+        };
+        let parameter_slots = function.parameters.iter().map(|parameter| parameter.slot).collect();
+        subroutines.insert(name.clone(), Subroutine { parameter_slots, entry: Label(*next_label) });
+        *next_label += 1;
+    }
+    subroutines
+}
+
+/// Length of the body of `function` compiled as a subroutine, without saving the return address and returning
+pub(crate) fn subroutine_length(module: &Module, function: &Function) -> u64 {
+    let mut next_label = 0;
+    let subroutines = subroutines(module, |_| true, &mut next_label);
+    let body = Generator::new(function, &subroutines, &mut next_label).body();
+    let length: u64 = body.instructions.iter().map(Instruction::len).sum();
+    length - linkage_length(function.return_slot)
+}
+
+/// Length of a call to `function`, the arguments' addresses taken as long as its slots
+pub(crate) fn call_length(function: &Function) -> u64 {
+    let number_length = |number: u64| get_number(&Num::from(number)).len() as u64;
+    let arguments: u64 = function.parameters.iter().map(|parameter| 2 * number_length(parameter.slot) + 2).sum();
+    arguments + 2
+}
+
+/// Length of the code saving the return address and returning, around the body of a subroutine
+pub(crate) fn linkage_length(return_slot: u64) -> u64 {
+    2 * get_number(&Num::from(return_slot)).len() as u64 + 8
+}
+
+/// Cost of running the body of `function` once, every block weighted by how often it runs, without getting in and out of a subroutine.
+/// Copied into a call its parameters are the caller's variables, otherwise they hold the addresses of the arguments.
+/// Only the scalars among `registers` may go to registers. The arguments are taken to be where the parameters' slots are.
+pub(crate) fn body_cost(module: &Module, function: &Function, copied: bool, registers: &HashSet<String>) -> u64 {
+    let mut function = function.clone();
+    if copied {
+        let parameters = std::mem::take(&mut function.parameters)
+            .into_iter()
+            .map(|parameter| Local { size: parameter.array.then_some(1), address: parameter.slot, name: parameter.name });
+        function.locals.extend(parameters);
+    }
+    let locals = function.locals.iter().filter(|local| registers.contains(&local.name)).cloned().collect();
+    let mut candidates = Function { locals, ..function.clone() };
+    allocate_registers(&mut candidates);
+    function.registers = candidates.registers;
+    let mut next_label = 0;
+    let subroutines = subroutines(module, |_| true, &mut next_label);
+    let mut generator = Generator::new(&function, &subroutines, &mut next_label);
+    let order = layout(&function);
+    let frequencies = frequencies(&function);
+    let mut cost: f64 = 0.0;
+    for (position, index) in order.iter().enumerate() {
+        let block = &function.blocks[*index];
+        generator.definitions = block.operations.iter().filter_map(|operation| Some((operation.defined()?, operation))).collect();
+        let mut instructions: Vec<Instruction> = block.operations.iter().flat_map(|operation| generator.operation(operation)).collect();
+        if block.terminator != Terminator::Return {
+            instructions.extend(generator.terminator(&block.terminator, order.get(position + 1).copied()));
         }
+        let block_cost: u64 = instructions.iter().map(Instruction::cost).sum();
+        cost += block_cost as f64 * frequencies[*index];
     }
-    fn rename_indentifier(&self, identifier: Identifier) -> Identifier {
-        match identifier {
-            Identifier::Base(id) => Identifier::Base((format!("{}@{}", id.0, self.name), id.1)),
-            Identifier::NumIndexed(id, num) => {
-                Identifier::NumIndexed((format!("{}@{}", id.0, self.name), id.1), num)
-            }
-            Identifier::PidIndexed(id, index_id) => Identifier::PidIndexed(
-                (format!("{}@{}", id.0, self.name), id.1),
-                (format!("{}@{}", index_id.0, self.name), id.1),
-            ),
+    cost as u64
+}
+
+/// How many times every block runs for one run of `function`, guessing that a loop goes round `LOOP_WEIGHT` times
+/// and that each way out of a branch staying in the same loops is taken as often
+fn frequencies(function: &Function) -> Vec<f64> {
+    let depths = function.loop_depths();
+    let mut shares = vec![0.0; function.blocks.len()];
+    if let Some(entry) = shares.first_mut() {
+        *entry = 1.0;
+    }
+    // Blocks come before the blocks they reach, the jumps back of loops aside
+    for (index, block) in function.blocks.iter().enumerate() {
+        let successors: Vec<BlockId> = block.terminator.successors().into_iter().filter(|successor| successor.0 > index).collect();
+        let ways = match successors.iter().all(|successor| depths[successor.0] == depths[index]) {
+            true => successors.len(),
+            false => 1,
+        };
+        for successor in successors {
+            shares[successor.0] += shares[index] / ways as f64;
         }
     }
+    shares.into_iter().zip(depths).map(|(share, depth)| share * (LOOP_WEIGHT as f64).powi(depth as i32)).collect()
 }
 
-#[derive(Debug)]
-pub struct Assembler {
-    pseudo_assembly: Vec<Instruction>,
-    procedures: HashMap<String, ProcedureBuilder>,
-    memory: HashMap<String, VariableVariant>,
-    initialisated_variables: HashSet<String>,
-    memory_pointer: u64,
-    ast: Program,
+/// Cost of a call to `function` and of getting in and out of it, the arguments' addresses taken as long as its slots
+pub(crate) fn call_cost(function: &Function) -> u64 {
+    let number_cost = |number: u64| get_number(&Num::from(number)).iter().map(Instruction::cost).sum::<u64>();
+    let arguments: u64 = function.parameters.iter().map(|parameter| 2 * number_cost(parameter.slot) + 1 + Instruction::Store(B).cost()).sum();
+    let linkage = 2 * number_cost(function.return_slot) + Instruction::Store(B).cost() + Instruction::Load(A).cost() + 6;
+    arguments + 2 + linkage
 }
 
-impl Assembler {
-    pub fn new(ast: Program) -> Result<Assembler, CompilerError> {
-        let mut procedures: HashMap<String, ProcedureBuilder> = HashMap::new();
-        if let Some(procedures_ast) = ast.0.clone() {
-            for procedure in procedures_ast {
-                if procedures.insert(procedure.0.0.0.clone(), ProcedureBuilder::new(procedure.clone())).is_some() {
-                    Err(CompilerError::DuplicateProcedureDeclaration(procedure.0.0.0.clone(), procedure.0.0.1.clone()))?;
-                }
-            }
+/// Builds the code of one function, the registers `A`, `B`, `C` and `H` are scratch
+struct Generator<'a> {
+    function: &'a Function,
+    subroutines: &'a HashMap<String, Subroutine>,
+    memory: HashMap<&'a str, VariableVariant>,
+    /// Registers holding variables, with the cells they are spilled to
+    registers: Vec<(Register, u64)>,
+    /// The operations assigning the temporaries of the block being built, their code is built where the temporary is used
+    definitions: HashMap<Temp, &'a Operation>,
+    /// One per block, only the ones jumped to stay
+    labels: Vec<Label>,
+    /// The code of every command of the source, indexed like `Function::commands`
+    command_code: Vec<Vec<Instruction>>,
+}
+
+impl<'a> Generator<'a> {
+    fn new(function: &'a Function, subroutines: &'a HashMap<String, Subroutine>, next_label: &mut usize) -> Generator<'a> {
+        let mut memory: HashMap<&str, VariableVariant> = HashMap::new();
+        for parameter in &function.parameters {
+            let reference = if parameter.array { VariableVariant::TableReference } else { VariableVariant::Reference };
+            memory.insert(&parameter.name, reference(parameter.slot));
         }
-        let mut memory_pointer: u64 = 0;
-        let mut memory: HashMap<String, VariableVariant> = HashMap::new();
-        if let Some(vars) = ast.1 .0.clone() {
-            for var in vars {
-                match var {
-                    DeclarationVariant::Base(id) => {
-                        memory.insert(id.0, VariableVariant::Atomic(memory_pointer));
-                        memory_pointer += 1;
-                    }
-                    DeclarationVariant::NumIndexed(id, size) => {
-                        memory.insert(id.0, VariableVariant::Table(memory_pointer, size));
-                        memory_pointer += size;
-                    }
-                }
+        for local in &function.locals {
+            memory.insert(&local.name, if local.size.is_some() { VariableVariant::Table(local.address) } else { VariableVariant::Atomic(local.address) });
+        }
+        let mut registers: Vec<(Register, u64)> = Vec::new();
+        for (register, name) in &function.registers {
+            if let Some(VariableVariant::Atomic(home)) = memory.get(name.as_str()).copied() {
+                memory.insert(name, VariableVariant::Register(*register, home));
+                registers.push((*register, home));
             }
         }
-        Ok(Assembler {
-            pseudo_assembly: vec![],
-            procedures,
-            memory,
-            memory_pointer,
-            ast,
-            initialisated_variables: HashSet::new(),
-        })
+        let labels = function.blocks.iter().map(|_| new_label(next_label)).collect();
+        let command_code = vec![Vec::new(); function.commands.len()];
+        Generator { function, subroutines, memory, registers, definitions: HashMap::new(), labels, command_code }
     }
-    pub fn assemble(&self) -> String {
-        let mut assembly: Vec<String> = Vec::new();
-        for instruction in &self.pseudo_assembly {
-            match instruction {
-                Instruction::Read => assembly.push("READ\n".to_string()),
-                Instruction::Write => assembly.push("WRITE\n".to_string()),
-                Instruction::Load(register) => assembly.push(format!("LOAD {register}\n")),
-                Instruction::Store(register) => assembly.push(format!("STORE {register}\n")),
-                Instruction::Add(register) => assembly.push(format!("ADD {register}\n")),
-                Instruction::Sub(register) => assembly.push(format!("SUB {register}\n")),
-                Instruction::Get(register) => assembly.push(format!("GET {register}\n")),
-                Instruction::Put(register) => assembly.push(format!("PUT {register}\n")),
-                Instruction::Rst(register) => assembly.push(format!("RST {register}\n")),
-                Instruction::Inc(register) => assembly.push(format!("INC {register}\n")),
-                Instruction::Dec(register) => assembly.push(format!("DEC {register}\n")),
-                Instruction::Shl(register) => assembly.push(format!("SHL {register}\n")),
-                Instruction::Shr(register) => assembly.push(format!("SHR {register}\n")),
-                Instruction::Jump(offset) => {
-                    assembly.push(format!("JUMP {}\n", offset + assembly.len() as i64))
-                }
-                Instruction::Jpos(offset) => {
-                    assembly.push(format!("JPOS {}\n", offset + assembly.len() as i64))
-                }
-                Instruction::Jzero(offset) => {
-                    assembly.push(format!("JZERO {}\n", offset + assembly.len() as i64))
-                }
-                Instruction::Strk => todo!(),
-                Instruction::Jumpr => todo!(),
-                Instruction::Halt => assembly.push("HALT\n".to_string()),
-                Instruction::Mul => {
-                    assembly.push("PUT e\n".to_string()); // 0 1
-                    assembly.push("RST d\n".to_string());
-                    assembly.push("GET c\n".to_string()); // 2 3
-                    assembly.push(format!("JZERO {}\n", assembly.len() + 14)); // 3 4
-                    assembly.push("SHR e\n".to_string());
-                    assembly.push("SHL e\n".to_string());
-                    assembly.push("GET c\n".to_string());
-                    assembly.push("SUB e\n".to_string());
-                    assembly.push(format!("JZERO {}\n", assembly.len() + 4)); // 8 9
-                    assembly.push("GET d\n".to_string());
-                    assembly.push("ADD b\n".to_string());
-                    assembly.push("PUT d\n".to_string());
-                    assembly.push("SHL b\n".to_string());
-                    assembly.push("SHR c\n".to_string());
-                    assembly.push("GET c\n".to_string());
-                    assembly.push("PUT e\n".to_string());
-                    assembly.push(format!("JUMP {}\n", assembly.len() - 14)); // 16  17
-                    assembly.push("GET d\n".to_string()); // 17 18
-                }
-                Instruction::Div => {
-                    assembly.push("RST d\n".to_string()); // 0 1
-                    assembly.push(format!("JZERO {}\n", assembly.len() + 21)); // 1 2
-                    assembly.push("GET c\n".to_string()); // 2 3
-                    assembly.push("SUB b\n".to_string());
-                    assembly.push(format!("JPOS {}\n", assembly.len() + 18)); // 4 5
-                    assembly.push("GET c\n".to_string());
-                    assembly.push("PUT e\n".to_string());
-                    assembly.push("RST f\n".to_string());
-                    assembly.push("INC f\n".to_string());
-                    assembly.push("GET e\n".to_string()); // 9 10
-                    assembly.push("SUB b\n".to_string());
-                    assembly.push(format!("JPOS {}\n", assembly.len() + 10)); // 11 12
-                    assembly.push("GET b\n".to_string());
-                    assembly.push("SUB e\n".to_string());
-                    assembly.push("PUT b\n".to_string());
-                    assembly.push("GET d\n".to_string());
-                    assembly.push("ADD f\n".to_string());
-                    assembly.push("PUT d\n".to_string());
-                    assembly.push("SHL e\n".to_string());
-                    assembly.push("SHL f\n".to_string());
-                    assembly.push(format!("JUMP {}\n", assembly.len() - 11)); // 20 21
-                    assembly.push(format!("JUMP {}\n", assembly.len() - 19)); // 21 22
-                    assembly.push("GET d\n".to_string()); // 22 23
-                }
-                Instruction::Mod => {
-                    assembly.push("RST d\n".to_string()); // 0 1
-                    assembly.push(format!("JZERO {}\n", assembly.len() + 21)); // 1 2
-                    assembly.push("GET c\n".to_string()); // 2 3
-                    assembly.push("SUB b\n".to_string());
-                    assembly.push(format!("JPOS {}\n", assembly.len() + 19)); // 4 5
-                    assembly.push("GET c\n".to_string());
-                    assembly.push("PUT e\n".to_string());
-                    assembly.push("RST f\n".to_string());
-                    assembly.push("INC f\n".to_string());
-                    assembly.push("GET e\n".to_string()); // 9 10
-                    assembly.push("SUB b\n".to_string());
-                    assembly.push(format!("JPOS {}\n", assembly.len() + 10)); // 11 12
-                    assembly.push("GET b\n".to_string());
-                    assembly.push("SUB e\n".to_string());
-                    assembly.push("PUT b\n".to_string());
-                    assembly.push("GET d\n".to_string());
-                    assembly.push("ADD f\n".to_string());
-                    assembly.push("PUT d\n".to_string());
-                    assembly.push("SHL e\n".to_string());
-                    assembly.push("SHL f\n".to_string());
-                    assembly.push(format!("JUMP {}\n", assembly.len() - 11)); // 20 21
-                    assembly.push(format!("JUMP {}\n", assembly.len() - 19)); // 21 22
-                    assembly.push("RST b\n".to_string()); // 22 23
-                    assembly.push("GET b\n".to_string()); // 23 24
-                }
-            }
+    /// Procedures start with saving the return address left in `A` by `STRK` and return with `JUMPR`, main ends with `HALT`
+    fn body(mut self) -> Body {
+        let function = self.function;
+        let mut instructions: Vec<Instruction> = Vec::new();
+        if let Some(subroutine) = function.name.as_ref().and_then(|name| self.subroutines.get(name)) {
+            // `STRK` left its own address in `A`, the call goes on after the `JUMP` following it
+            instructions.extend([Instruction::Label(subroutine.entry), Instruction::Inc(A), Instruction::Inc(A), Instruction::Put(C)]);
+            instructions.extend(get_number(&Num::from(function.return_slot)));
+            instructions.extend([Instruction::Put(B), Instruction::Get(C), Instruction::Store(B)]);
         }
-        let mut assembled = "".to_string();
-        for i in assembly {
-            assembled += &i;
+        let order = layout(function);
+        for (position, index) in order.iter().enumerate() {
+            let block = &function.blocks[*index];
+            instructions.push(Instruction::Label(self.labels[*index]));
+            self.definitions = block.operations.iter().filter_map(|operation| Some((operation.defined()?, operation))).collect();
+            for (operation, origin) in block.operations.iter().zip(&block.origins) {
+                let code = self.operation(operation);
+                self.command_code[*origin].extend(&code);
+                instructions.extend(code);
+            }
+            let code = self.terminator(&block.terminator, order.get(position + 1).copied());
+            if let Some(origin) = block.terminator_origin {
+                self.command_code[origin].extend(&code);
+            }
+            instructions.extend(code);
         }
-        assembled
-    }
-    pub fn construct(&mut self) -> Result<(), CompilerError>{
-        self.construct_main()?;
-        self.pseudo_assembly.push(Instruction::Halt);
-        Ok(())
+        let targets: HashSet<Label> = instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Jump(label) | Instruction::Jpos(label) | Instruction::Jzero(label) => Some(*label),
+                _ => None,
+            })
+            .collect();
+        instructions.retain(|instruction| !matches!(instruction, Instruction::Label(label) if self.labels.contains(label) && !targets.contains(label)));
+        let commands = function.commands.iter().cloned().zip(self.command_code).collect();
+        Body { procedure: function.name.clone(), instructions, commands }
     }
-    fn construct_main(&mut self) -> Result<(), CompilerError>{
-        let commands = self.ast.1 .1.clone();
-        let mut constructed_commands: Vec<Vec<Instruction>> = vec![];
-        for command in commands {
-            constructed_commands.push(self.construct_command(command)?)
+    /// Jumps where the block goes, unless it is the block placed next
+    fn terminator(&self, terminator: &Terminator, next: Option<usize>) -> Vec<Instruction> {
+        match terminator {
+            Terminator::Jump(target) if Some(target.0) == next => Vec::new(),
+            Terminator::Jump(target) => vec![Instruction::Jump(self.labels[target.0])],
+            Terminator::Branch(relation, operand_0, operand_1, yes, no) => {
+                let (target, other) = if jumps_if_true(*relation) { (yes, no) } else { (no, yes) };
+                let mut instructions = self.test(*relation, operand_0, operand_1, self.labels[target.0]);
+                if Some(other.0) != next {
+                    instructions.push(Instruction::Jump(self.labels[other.0]));
+                }
+                instructions
+            },
+            Terminator::Return if self.function.name.is_some() => {
+                let mut instructions = get_number(&Num::from(self.function.return_slot));
+                instructions.extend([Instruction::Load(A), Instruction::Jumpr(A)]);
+                instructions
+            },
+            Terminator::Return => vec![Instruction::Halt],
         }
-        for command in constructed_commands {
-            self.pseudo_assembly.extend(command);
+    }
+    /// Compares the operands and jumps to `target` when the relation holds or when it does not, as `jumps_if_true` tells
+    fn test(&self, relation: Relation, operand_0: &Operand, operand_1: &Operand, target: Label) -> Vec<Instruction> {
+        let mut instructions: Vec<Instruction> = Vec::new();
+        match relation {
+            // Either difference being positive means the values differ
+            Relation::Equal | Relation::NotEqual => {
+                instructions.extend(self.value(operand_0));
+                instructions.push(Instruction::Put(B));
+                instructions.extend(self.value(operand_1));
+                instructions.extend([Instruction::Put(C), Instruction::Sub(B), Instruction::Jpos(target)]);
+                instructions.extend([Instruction::Get(B), Instruction::Sub(C), Instruction::Jpos(target)]);
+            },
+            // `operand_0 - operand_1` is positive
+            Relation::Greater | Relation::LowerOrEqual => {
+                instructions.extend(self.value(operand_1));
+                instructions.push(Instruction::Put(B));
+                instructions.extend(self.value(operand_0));
+                instructions.extend([Instruction::Sub(B), Instruction::Jpos(target)]);
+            },
+            // `operand_1 - operand_0` is positive
+            Relation::Lower | Relation::GreaterOrEqual => {
+                instructions.extend(self.value(operand_0));
+                instructions.push(Instruction::Put(B));
+                instructions.extend(self.value(operand_1));
+                instructions.extend([Instruction::Sub(B), Instruction::Jpos(target)]);
+            },
         }
-        Ok(())
+        instructions
     }
-    fn construct_command(&mut self, command: Command) -> Result<Vec<Instruction>, CompilerError> {
-        match command {
-            Command::Assign(identifier, expression) => {
-                let mut instructions: Vec<Instruction> = Vec::new();
-                let id = match identifier.clone() {
-                    Identifier::Base(id) => id,
-                    Identifier::NumIndexed(id, _) => id,
-                    Identifier::PidIndexed(id, _) => id,
+    /// Temporaries are left for their use
+    fn operation(&self, operation: &Operation) -> Vec<Instruction> {
+        let mut instructions: Vec<Instruction> = Vec::new();
+        match operation {
+            Operation::Binary(_, _, _, _) | Operation::Load(_, _) => {},
+            Operation::Store(place, operand) => match self.register_of(place) {
+                Some(register) => {
+                    instructions.extend(self.value(operand));
+                    instructions.push(Instruction::Put(register));
+                },
+                // The address cannot wait in `G`, so it comes after the value
+                None if self.holds_variable(G) => {
+                    instructions.extend(self.value(operand));
+                    instructions.push(Instruction::Put(B));
+                    instructions.extend(self.pointer(place));
+                    instructions.extend([Instruction::Put(C), Instruction::Get(B), Instruction::Store(C)]);
+                },
+                None => {
+                    instructions.extend(self.pointer(place));
+                    instructions.push(Instruction::Put(G));
+                    instructions.extend(self.value(operand));
+                    instructions.push(Instruction::Store(G));
+                },
+            },
+            Operation::Read(place) => match self.register_of(place) {
+                Some(register) => instructions.extend([Instruction::Read, Instruction::Put(register)]),
+                None if self.holds_variable(G) => {
+                    instructions.extend([Instruction::Read, Instruction::Put(B)]);
+                    instructions.extend(self.pointer(place));
+                    instructions.extend([Instruction::Put(C), Instruction::Get(B), Instruction::Store(C)]);
+                },
+                None => {
+                    instructions.extend(self.pointer(place));
+                    instructions.extend([Instruction::Put(G), Instruction::Read, Instruction::Store(G)]);
+                },
+            },
+            Operation::Write(operand) => {
+                instructions.extend(self.value(operand));
+                instructions.push(Instruction::Write);
+            },
+            Operation::Call(name, arguments) => {
+                let Some(subroutine) = self.subroutines.get(name) else {
+                    return instructions;
                 };
-                self.initialisated_variables.insert(id.0.clone());
-                instructions.extend(self.get_pointer_from_identifier(identifier)?);
-                instructions.push(Instruction::Put(G));
-                instructions.extend(self.construct_expression(expression)?);
-                instructions.push(Instruction::Store(G));
-                Ok(instructions)
-            }
-            Command::If(condition, commands, else_commands) => {
-                let mut instructions: Vec<Instruction> = Vec::new();
-                let mut sub_instuctions: Vec<Instruction> = Vec::new();
-                for command in commands {
-                    sub_instuctions.extend(self.construct_command(command)?);
-                }
-                let sub_instructions_length: u64 = sub_instuctions.iter().map(|i| i.len()).sum();
-                let mut sub_else_instuctions: Vec<Instruction> = Vec::new();
-                if let Some(else_commands) = else_commands {
-                    for command in else_commands {
-                        sub_else_instuctions.extend(self.construct_command(command)?);
-                    }
+                // The subroutine uses the registers for itself, and the arguments have to be in memory
+                instructions.extend(self.spill(&VARIABLE_REGISTERS));
+                for (argument, slot) in arguments.iter().zip(&subroutine.parameter_slots) {
+                    instructions.extend(get_number(&Num::from(*slot)));
+                    instructions.push(Instruction::Put(B));
+                    instructions.extend(get_address(self.variable(argument)));
+                    instructions.push(Instruction::Store(B));
                 }
-                let sub_else_instruction_length: u64 =
-                    sub_else_instuctions.iter().map(|i| i.len()).sum();
-                match condition {
-                    Condition::Equal(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(C));
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_instructions_length as i64 + 5));
-                        cond_instructions.push(Instruction::Get(B));
-                        cond_instructions.push(Instruction::Sub(C));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_instructions_length as i64 + 2));
-
-                        instructions.extend(cond_instructions);
-                        instructions.extend(sub_instuctions);
-                        instructions
-                            .push(Instruction::Jump(sub_else_instruction_length as i64 + 1));
-                        instructions.extend(sub_else_instuctions);
-                    }
-                    Condition::NotEqual(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(C));
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_else_instruction_length as i64 + 5));
-                        cond_instructions.push(Instruction::Get(B));
-                        cond_instructions.push(Instruction::Sub(C));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_else_instruction_length as i64 + 2));
-
-                        instructions.extend(cond_instructions);
-                        instructions.extend(sub_else_instuctions);
-                        instructions.push(Instruction::Jump(sub_instructions_length as i64 + 1));
-                        instructions.extend(sub_instuctions);
-                    }
-                    Condition::Greater(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_else_instruction_length as i64 + 2));
-
-                        instructions.extend(cond_instructions);
-                        instructions.extend(sub_else_instuctions);
-                        instructions.push(Instruction::Jump(sub_instructions_length as i64 + 1));
-                        instructions.extend(sub_instuctions);
-                    }
-                    Condition::Lower(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_else_instruction_length as i64 + 2));
-
-                        instructions.extend(cond_instructions);
-                        instructions.extend(sub_else_instuctions);
-                        instructions.push(Instruction::Jump(sub_instructions_length as i64 + 1));
-                        instructions.extend(sub_instuctions);
-                    }
-                    Condition::GreaterOrEqual(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_instructions_length as i64 + 2));
-
-                        instructions.extend(cond_instructions);
-                        instructions.extend(sub_instuctions);
-                        instructions
-                            .push(Instruction::Jump(sub_else_instruction_length as i64 + 1));
-                        instructions.extend(sub_else_instuctions);
-                    }
-                    Condition::LowerOrEqual(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_instructions_length as i64 + 2));
-
-                        instructions.extend(cond_instructions);
-                        instructions.extend(sub_instuctions);
+                instructions.extend([Instruction::Strk(A), Instruction::Jump(subroutine.entry)]);
+                instructions.extend(self.reload(&VARIABLE_REGISTERS));
+            },
+        }
+        instructions
+    }
+    fn variable(&self, name: &str) -> VariableVariant {
+        self.memory.get(name).copied().unwrap_or(VariableVariant::Atomic(0))
+    }
+    /// The register holding `place`, if it is a scalar kept in one
+    fn register_of(&self, place: &Place) -> Option<Register> {
+        match place {
+            Place::Variable(name) => match self.variable(name) {
+                VariableVariant::Register(register, _) => Some(register),
+                _ => None,
+            },
+            Place::Element(_, _) => None,
+        }
+    }
+    /// Whether `register` holds a variable in this function
+    fn holds_variable(&self, register: Register) -> bool {
+        self.registers.iter().any(|(held, _)| *held == register)
+    }
+    /// Puts the value of `operand` into `A`
+    fn value(&self, operand: &Operand) -> Vec<Instruction> {
+        match operand {
+            Operand::Const(num) => get_number(num),
+            Operand::Temp(temp) => match self.definitions.get(temp) {
+                Some(Operation::Load(_, place)) => match self.register_of(place) {
+                    Some(register) => vec![Instruction::Get(register)],
+                    None => {
+                        let mut instructions = self.pointer(place);
+                        instructions.push(Instruction::Load(A));
                         instructions
-                            .push(Instruction::Jump(sub_else_instruction_length as i64 + 1));
-                        instructions.extend(sub_else_instuctions);
-                    }
-                }
-                Ok(instructions)
-            }
-            Command::While(condition, commands) => {
-                let mut instructions: Vec<Instruction> = Vec::new();
-                let mut sub_instuctions: VecDeque<Instruction> = VecDeque::new();
-                for command in commands {
-                    sub_instuctions.extend(self.construct_command(command)?);
-                }
-                let sub_instructions_length: u64 = sub_instuctions.iter().map(|i| i.len()).sum();
-                let cond_instructions = match condition {
-                    Condition::Equal(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(C));
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_instructions_length as i64 + 5));
-                        cond_instructions.push(Instruction::Get(B));
-                        cond_instructions.push(Instruction::Sub(C));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_instructions_length as i64 + 2));
-                        cond_instructions
-                    }
-                    Condition::NotEqual(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(C));
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions.push(Instruction::Jpos(5));
-                        cond_instructions.push(Instruction::Get(B));
-                        cond_instructions.push(Instruction::Sub(C));
-                        cond_instructions.push(Instruction::Jpos(2));
-                        cond_instructions
-                            .push(Instruction::Jump(sub_instructions_length as i64 + 2));
-                        cond_instructions
-                    }
-                    Condition::Greater(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions.push(Instruction::Jpos(2));
-                        cond_instructions
-                            .push(Instruction::Jump(sub_instructions_length as i64 + 2));
-                        cond_instructions
-                    }
-                    Condition::Lower(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions.push(Instruction::Jpos(2));
-                        cond_instructions
-                            .push(Instruction::Jump(sub_instructions_length as i64 + 2));
-                        cond_instructions
-                    }
-                    Condition::GreaterOrEqual(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_instructions_length as i64 + 2));
-                        cond_instructions
-                    }
-                    Condition::LowerOrEqual(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_instructions_length as i64 + 2));
-                        cond_instructions
-                    }
-                };
-                let cond_instructions_length: u64 = cond_instructions.iter().map(|i| i.len()).sum();
-                instructions.extend(cond_instructions);
-                instructions.extend(sub_instuctions);
-                instructions.push(Instruction::Jump(
-                    -((sub_instructions_length + cond_instructions_length) as i64),
-                ));
-                Ok(instructions)
-            }
-            Command::Repeat(commands, condition) => {
-                let mut instructions: Vec<Instruction> = Vec::new();
-                let mut sub_instuctions: VecDeque<Instruction> = VecDeque::new();
-                for command in commands {
-                    sub_instuctions.extend(self.construct_command(command)?);
-                }
-                let sub_instructions_length: u64 = sub_instuctions.iter().map(|i| i.len()).sum();
-
-                let cond_instructions = match condition {
-                    Condition::Equal(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(C));
-                        cond_instructions.push(Instruction::Sub(B));
-                        let cond_instructions_length: u64 =
-                            cond_instructions.iter().map(|i| i.len()).sum();
-                        cond_instructions.push(Instruction::Jpos(
-                            -((cond_instructions_length + sub_instructions_length) as i64),
-                        ));
-                        cond_instructions.push(Instruction::Get(B));
-                        cond_instructions.push(Instruction::Sub(C));
-                        cond_instructions.push(Instruction::Jpos(
-                            -((cond_instructions_length + sub_instructions_length + 3) as i64),
-                        ));
-                        cond_instructions
-                    }
-                    Condition::NotEqual(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(C));
-                        cond_instructions.push(Instruction::Sub(B));
-                        let cond_instructions_length: u64 =
-                            cond_instructions.iter().map(|i| i.len()).sum();
-                        cond_instructions.push(Instruction::Jpos(5));
-                        cond_instructions.push(Instruction::Get(B));
-                        cond_instructions.push(Instruction::Sub(C));
-                        cond_instructions.push(Instruction::Jpos(2));
-                        cond_instructions.push(Instruction::Jump(
-                            -((cond_instructions_length + sub_instructions_length) as i64),
-                        ));
-                        cond_instructions
-                    }
-                    Condition::Greater(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions.push(Instruction::Jpos(2));
-                        let cond_instructions_length: u64 =
-                            cond_instructions.iter().map(|i| i.len()).sum();
-                        cond_instructions.push(Instruction::Jump(
-                            -((cond_instructions_length + sub_instructions_length) as i64),
-                        ));
-                        cond_instructions
-                    }
-                    Condition::Lower(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions.push(Instruction::Jpos(2));
-                        let cond_instructions_length: u64 =
-                            cond_instructions.iter().map(|i| i.len()).sum();
-                        cond_instructions.push(Instruction::Jump(
-                            -((cond_instructions_length + sub_instructions_length) as i64),
-                        ));
-                        cond_instructions
-                    }
-                    Condition::GreaterOrEqual(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        let cond_instructions_length: u64 =
-                            cond_instructions.iter().map(|i| i.len()).sum();
-                        cond_instructions.push(Instruction::Jpos(
-                            -((cond_instructions_length + sub_instructions_length) as i64),
-                        ));
-                        cond_instructions
-                    }
-                    Condition::LowerOrEqual(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        let cond_instructions_length: u64 =
-                            cond_instructions.iter().map(|i| i.len()).sum();
-                        cond_instructions.push(Instruction::Jpos(
-                            -((cond_instructions_length + sub_instructions_length) as i64),
-                        ));
-                        cond_instructions
-                    }
-                };
-                instructions.extend(sub_instuctions);
-                instructions.extend(cond_instructions);
-                Ok(instructions)
-            }
-            Command::ProcCall((procedure_id, arguments)) => {
-                let mut instructions: Vec<Instruction> = Vec::new();
-                let ids: Vec<String> = arguments.iter().map(|arg| arg.0.clone()).collect();
-                for id in ids {
-                    if  (&id).contains(&format!("@{}", procedure_id.0)) {
-                        return Err(CompilerError::RecursiveProcedureCall(procedure_id.0, procedure_id.1));
-                    }
-                }
-                
-                let builder = self.procedures.clone().get(&procedure_id.0).ok_or(CompilerError::UndeclaredProcedure(procedure_id.0.clone(), procedure_id.1))?.clone();
-                if builder.declared_arguments.len() != arguments.len() {
-                    return Err(CompilerError::WrongNumberOfArguments(procedure_id.0.clone(), procedure_id.1));
-                }
-                if let Some(declarations) = &builder.declarations {
-                    for declaration in declarations {
-                        match declaration {
-                            DeclarationVariant::Base(id) => {
-                                self.memory.insert(format!("{}@{}", id.0, procedure_id.0), VariableVariant::Atomic(self.memory_pointer));
-                                self.memory_pointer += 1;
-                            },
-                            DeclarationVariant::NumIndexed(id, length) => {
-                                self.memory.insert(format!("{}@{}", id.0, procedure_id.0), VariableVariant::Table(self.memory_pointer, *length));
-                                self.memory_pointer += length;
-                            },
-                        }
-                    }
-                }
-
-                for (argument, declared_argument) in arguments.iter().zip(&builder.declared_arguments) {
-                    if let Some(declarations) = &builder.declarations{
-                        for declaration in declarations {
-                            let id = match declaration {
-                                DeclarationVariant::Base(id) => id,
-                                DeclarationVariant::NumIndexed(id, _) => id,
-                            };
-                            let arg_id = match declared_argument {
-                                ArgumentsDeclarationVariant::Base(id) => id,
-                                ArgumentsDeclarationVariant::Table(id) => id,
-                            };
-                            if id.0 == arg_id.0 {
-                                return Err(CompilerError::DuplicateVariableDeclaration(id.0.clone(), id.1));
-                            }
-                        }
-                    }                    
-                    let pointee = self.memory.get(argument.0.as_str()).unwrap();
-                    match declared_argument {
-                        ArgumentsDeclarationVariant::Base(id) => {
-                            match pointee {
-                                VariableVariant::Atomic(pointer) => {
-                                    self.initialisated_variables.insert(argument.0.clone());
-                                    self.memory.insert(format!("{}@{}", id.0, procedure_id.0), VariableVariant::Atomic(*pointer));
-                                    self.initialisated_variables.insert(format!("{}@{}", id.0, procedure_id.0));
-                                },
-                                VariableVariant::Table(_, _) => return Err(CompilerError::WrongArgumentType(id.0.clone(), id.1)),
-                            }
-                        },
-                        ArgumentsDeclarationVariant::Table(id) => {
-                            match pointee {
-                                VariableVariant::Atomic(_) => return Err(CompilerError::WrongArgumentType(id.0.clone(), id.1)),
-                                VariableVariant::Table(start, size) => {
-                                    self.initialisated_variables.insert(argument.0.clone());
-                                    self.memory.insert(format!("{}@{}", id.0, procedure_id.0), VariableVariant::Table(*start, *size));
-                                    self.initialisated_variables.insert(format!("{}@{}", id.0, procedure_id.0));
-                                },
-                            }
-                        },
-                    }
-                }
-                for command in &builder.commands {
-                    instructions.extend(self.construct_command(command.clone())?);
-                }
-                Ok(instructions)
-            }
-            Command::Read(identifier) => {
-                let id = match identifier.clone() {
-                    Identifier::Base(id) => id,
-                    Identifier::NumIndexed(id, _) => id,
-                    Identifier::PidIndexed(id, _) => id,
-                };
-                self.initialisated_variables.insert(id.0.clone());
-                let mut instructions: Vec<Instruction> = Vec::new();
-                instructions.extend(self.get_pointer_from_identifier(identifier)?);
-                instructions.push(Instruction::Put(G));
-                instructions.push(Instruction::Read);
-                instructions.push(Instruction::Store(G));
-                Ok(instructions)
-            }
-            Command::Write(value) => {
-                let mut instructions: Vec<Instruction> = self.extract_value(value)?;
-                instructions.push(Instruction::Write);
-                Ok(instructions)
-            }
+                    },
+                },
+                Some(Operation::Binary(_, op, operand_0, operand_1)) => self.binary(*op, operand_0, operand_1),
+                _ => Vec::new(),
+            },
         }
     }
-    /// Constructs expressions into PseudoAssembly
-    fn construct_expression(&self, expression: Expression) -> Result<Vec<Instruction>, CompilerError> {
-        match expression {
-            Expression::Val(value) => {
-                self.check_if_initialised(value.clone());
-                self.extract_value(value)
+    /// Puts the address of `place` into `A`. Sometimes uses `H`.
+    fn pointer(&self, place: &Place) -> Vec<Instruction> {
+        let variable = self.variable(place.name());
+        match place {
+            Place::Variable(_) => get_address(variable),
+            Place::Element(_, Operand::Const(index)) => match variable {
+                VariableVariant::Table(start) => get_number(&(Num::from(start) + index)),
+                _ => {
+                    let mut instructions = get_address(variable);
+                    if !index.is_zero() {
+                        instructions.push(Instruction::Put(H));
+                        instructions.extend(get_number(index));
+                        instructions.push(Instruction::Add(H));
+                    }
+                    instructions
+                },
             },
-            Expression::Add(value_0, value_1) => {
-                self.check_if_initialised(value_0.clone());
-                let mut instructions = self.extract_value(value_0)?;
+            Place::Element(_, index) => {
+                let mut instructions = self.value(index);
+                instructions.push(Instruction::Put(H));
+                instructions.extend(get_address(variable));
+                instructions.push(Instruction::Add(H));
+                instructions
+            },
+        }
+    }
+    /// Puts `operand_0 op operand_1` into `A`. A constant operand avoids the loops of `Mul`, `Div` and `Mod` where `routine_of` tells.
+    fn binary(&self, op: BinaryOp, operand_0: &Operand, operand_1: &Operand) -> Vec<Instruction> {
+        let mut instructions: Vec<Instruction> = Vec::new();
+        if let Some(routine) = routine_of(op, operand_0, operand_1) {
+            instructions.extend(self.value(operand_0));
+            instructions.push(Instruction::Put(B));
+            instructions.extend(self.value(operand_1));
+            instructions.push(Instruction::Put(C));
+            instructions.extend(self.call_routine(routine));
+            return instructions;
+        }
+        let (constant_0, constant_1) = (constant(operand_0), constant(operand_1));
+        // The exponent of a power of two
+        let shifts = |num: &Num| num.bits().saturating_sub(1);
+        match (op, constant_0, constant_1) {
+            (_, Some(num_0), Some(num_1)) => instructions.extend(get_number(&op.fold(num_0, num_1))),
+            (BinaryOp::Add, _, _) => {
+                instructions.extend(self.value(operand_0));
                 instructions.push(Instruction::Put(B));
-                self.check_if_initialised(value_1.clone());
-                instructions.extend(self.extract_value(value_1)?);
+                instructions.extend(self.value(operand_1));
                 instructions.push(Instruction::Add(B));
-                Ok(instructions)
-            }
-            Expression::Substract(value_0, value_1) => {
-                self.check_if_initialised(value_1.clone());
-                let mut instructions = self.extract_value(value_1)?;
+            },
+            (BinaryOp::Sub, _, _) => {
+                instructions.extend(self.value(operand_1));
                 instructions.push(Instruction::Put(B));
-                self.check_if_initialised(value_0.clone());
-                instructions.extend(self.extract_value(value_0)?);
+                instructions.extend(self.value(operand_0));
                 instructions.push(Instruction::Sub(B));
-                Ok(instructions)
-            }
-            Expression::Multiply(value_0, value_1) => {
-                self.check_if_initialised(value_0.clone());
-                let mut instructions = self.extract_value(value_0)?;
-                instructions.push(Instruction::Put(B));
-                self.check_if_initialised(value_1.clone());
-                instructions.extend(self.extract_value(value_1)?);
-                instructions.push(Instruction::Put(C));
-                instructions.push(Instruction::Mul);
-                Ok(instructions)
-            }
-            Expression::Divide(value_0, value_1) => {
-                self.check_if_initialised(value_0.clone());
-                self.check_if_initialised(value_1.clone());
-                let mut instructions = self.extract_value(value_0)?;
-                instructions.push(Instruction::Put(B));
-                instructions.extend(self.extract_value(value_1)?);
-                instructions.push(Instruction::Put(C));
-                instructions.push(Instruction::Div);
-                Ok(instructions)
-            }
-            Expression::Modulo(value_0, value_1) => {
-                self.check_if_initialised(value_0.clone());
-                self.check_if_initialised(value_1.clone());
-                let mut instructions = self.extract_value(value_0)?;
+            },
+            (BinaryOp::Mul, _, Some(num)) => instructions.extend(self.multiply_by_constant(operand_0, num)),
+            (BinaryOp::Mul, Some(num), _) => instructions.extend(self.multiply_by_constant(operand_1, num)),
+            // Dividing 0 or by 0 gives 0, and so does the remainder of 0 or modulo 0 or 1
+            (BinaryOp::Div | BinaryOp::Mod, Some(num), _) if num.is_zero() => instructions.push(Instruction::Rst(A)),
+            (BinaryOp::Div, _, Some(num)) if num.is_zero() => instructions.push(Instruction::Rst(A)),
+            (BinaryOp::Mod, _, Some(num)) if num.bits() <= 1 => instructions.push(Instruction::Rst(A)),
+            (BinaryOp::Div | BinaryOp::Shr, _, Some(num)) => {
+                let count = if op == BinaryOp::Div { shifts(num) } else { num.to_u64().unwrap_or(0) };
+                instructions.extend(self.value(operand_0));
+                instructions.extend((0..count).map(|_| Instruction::Shr(A)));
+            },
+            (BinaryOp::Shl, _, Some(num)) => {
+                instructions.extend(self.value(operand_0));
+                instructions.extend((0..num.to_u64().unwrap_or(0)).map(|_| Instruction::Shl(A)));
+            },
+            // `x - x / 2^k * 2^k`
+            (BinaryOp::Mod, _, Some(num)) => {
+                instructions.extend(self.value(operand_0));
                 instructions.push(Instruction::Put(B));
-                instructions.extend(self.extract_value(value_1)?);
-                instructions.push(Instruction::Put(C));
-                instructions.push(Instruction::Mod);
-                Ok(instructions)
-            }
+                instructions.extend((0..shifts(num)).map(|_| Instruction::Shr(A)));
+                instructions.extend((0..shifts(num)).map(|_| Instruction::Shl(A)));
+                instructions.extend([Instruction::Put(C), Instruction::Get(B), Instruction::Sub(C)]);
+            },
+            // `reduce` only makes shifts by a constant number of bits
+            (BinaryOp::Shl | BinaryOp::Shr, _, None) => unreachable!("{op} by a variable number of bits"),
+            (BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod, _, _) => unreachable!("{op} of {operand_0} and {operand_1} is left to a routine"),
         }
+        instructions
     }
-    /// Gets the `value` and puts it into the `A` register
-    fn extract_value(&self, value: Value) -> Result<Vec<Instruction>, CompilerError> {
-        match value {
-            Value::Num(num) => Ok(get_number(num)),
-            Value::Id(identifier) => {
-                let mut sub_instructions = self.get_pointer_from_identifier(identifier)?;
-                sub_instructions.push(Instruction::Load(A));
-                Ok(sub_instructions)
+    /// Multiplies with shifts and additions going through the bits of `num`, which is never longer nor slower than the generic loop
+    fn multiply_by_constant(&self, operand: &Operand, num: &Num) -> Vec<Instruction> {
+        if num.is_zero() {
+            return vec![Instruction::Rst(A)];
+        }
+        let mut instructions = self.value(operand);
+        if num.count_ones() > 1 {
+            instructions.push(Instruction::Put(B));
+        }
+        for bit in (0..num.bits() - 1).rev() {
+            instructions.push(Instruction::Shl(A));
+            if num.bit(bit) {
+                instructions.push(Instruction::Add(B));
             }
         }
+        instructions
     }
-    /// Puts the pointer to `identifier` into the `A` register. Sometimes uses the `H` register.
-    fn get_pointer_from_identifier(&self, identifier: Identifier) -> Result<Vec<Instruction>, CompilerError>{
-        match identifier {
-            Identifier::Base(id) => {
-                let variable = self.memory.get(&id.0).ok_or(CompilerError::UndeclaredVariable(id.0.clone(), id.1))?;
-                match variable {
-                    VariableVariant::Atomic(pointer) => Ok(get_number(*pointer)),
-                    VariableVariant::Table(_, _) => Err(CompilerError::IncorrectUseOfVariable(id.0, id.1)),
-                }
-            },
-            Identifier::NumIndexed(id, num) => {
-                let variable = self.memory.get(&id.0).ok_or(CompilerError::UndeclaredVariable(id.0.clone(), id.1))?;
-                let (start, size) = match variable {
-                    VariableVariant::Atomic(_) => {
-                        return Err(CompilerError::IncorrectUseOfVariable(id.0, id.1));
-                    }
-                    VariableVariant::Table(pointer, size) => (*pointer, *size),
-                };
-                if num >= size {
-                    return Err(CompilerError::IndexOutOfBounds(id.0, id.1));
-                }
-                Ok(get_number(start + num))
-            }
-            Identifier::PidIndexed(id, index_id) => {
-                let mut instructions: Vec<Instruction> = Vec::new();
-                if !self.initialisated_variables.contains(&index_id.0) {
-                    let id_for_warning = index_id.0.split('@').next().unwrap().to_string();
-                    println!("Warning: Variable {} used before initialisation", id_for_warning)
-                }
-                let variable = self.memory.get(&index_id.0).ok_or(CompilerError::UndeclaredVariable(index_id.0.clone(), index_id.1))?;
-                match variable {
-                    VariableVariant::Atomic(pointer) => instructions.extend(get_number(*pointer)),
-                    VariableVariant::Table(_, _) => {
-                        return Err(CompilerError::ArrayUsedAsIndex(id.0, id.1));
-                    }
-                };
-                instructions.push(Instruction::Load(A));
-                instructions.push(Instruction::Put(H));
-                match self.memory.get(&id.0).unwrap() {
-                    VariableVariant::Atomic(_) => {
-                        return Err(CompilerError::IncorrectUseOfVariable(id.0, id.1));
-                    }
-                    VariableVariant::Table(pointer, _) => {
-                        instructions.extend(get_number(*pointer));
-                    }
+    /// Stores the variables kept in any of `registers` to their cells, using `H` for the address
+    fn spill(&self, registers: &[Register]) -> Vec<Instruction> {
+        let mut instructions = Vec::new();
+        for (register, home) in self.registers.iter().filter(|(register, _)| registers.contains(register)) {
+            instructions.extend(get_number(&Num::from(*home)));
+            instructions.extend([Instruction::Put(H), Instruction::Get(*register), Instruction::Store(H)]);
+        }
+        instructions
+    }
+    /// Loads the variables kept in any of `registers` back from their cells
+    fn reload(&self, registers: &[Register]) -> Vec<Instruction> {
+        let mut instructions = Vec::new();
+        for (register, home) in self.registers.iter().filter(|(register, _)| registers.contains(register)) {
+            instructions.extend(get_number(&Num::from(*home)));
+            instructions.extend([Instruction::Load(A), Instruction::Put(*register)]);
+        }
+        instructions
+    }
+    /// `Mul`, `Div` or `Mod` with the operands in `B` and `C` and the second one in `A`, spilling the variables in the registers it clobbers
+    fn call_routine(&self, routine: Instruction) -> Vec<Instruction> {
+        let clobbered = clobbered_by(routine);
+        let mut instructions = self.spill(clobbered);
+        if instructions.is_empty() {
+            return vec![routine];
+        }
+        instructions.extend([Instruction::Get(C), routine, Instruction::Put(B)]);
+        instructions.extend(self.reload(clobbered));
+        instructions.push(Instruction::Get(B));
+        instructions
+    }
+}
+
+/// The order blocks are placed in: a block is followed by the one it falls through to, unless that one is placed already
+fn layout(function: &Function) -> Vec<usize> {
+    let mut order: Vec<usize> = Vec::new();
+    let mut placed = vec![false; function.blocks.len()];
+    for start in 0..function.blocks.len() {
+        let mut block = start;
+        while !placed[block] {
+            placed[block] = true;
+            order.push(block);
+            block = match &function.blocks[block].terminator {
+                Terminator::Jump(target) => target.0,
+                Terminator::Branch(relation, _, _, yes, no) => if jumps_if_true(*relation) { no.0 } else { yes.0 },
+                Terminator::Return => break,
+            };
+        }
+    }
+    order
+}
+
+fn new_label(next_label: &mut usize) -> Label {
+    *next_label += 1;
+    Label(*next_label - 1)
+}
+
+/// Pairs the most used scalars of `function` with the least clobbered registers, as long as the accesses saved
+/// outweigh spilling the register around every `Mul`, `Div`, `Mod` and call clobbering it.
+/// Variables that might be read before being assigned stay in memory, a register could hold anything then.
+/// So do the ones of copied procedures, whose cells other procedures use too and which a spill would overwrite.
+pub(crate) fn allocate_registers(function: &mut Function) {
+    let usage = Usage::of(function);
+    let mut candidates: Vec<(u64, &Local)> = function
+        .locals
+        .iter()
+        .filter(|local| local.size.is_none() && !local.name.contains('@') && !function.uninitialised.contains(&local.name))
+        .map(|local| (usage.uses.get(&local.name).copied().unwrap_or(0), local))
+        .collect();
+    candidates.sort_by(|candidate_0, candidate_1| candidate_1.0.cmp(&candidate_0.0).then(candidate_0.1.name.cmp(&candidate_1.1.name)));
+    let clobbers = |register: &Register| usage.clobbers.get(register).copied().unwrap_or(0);
+    let mut registers = VARIABLE_REGISTERS.to_vec();
+    registers.sort_by_key(clobbers);
+    let mut allocation = Vec::new();
+    for ((uses, local), register) in candidates.into_iter().zip(registers) {
+        let access = 50 + get_number(&Num::from(local.address)).len() as u64;
+        if uses.saturating_mul(access) <= clobbers(&register).saturating_mul(2 * access + 2) {
+            break;
+        }
+        allocation.push((register, local.name.clone()));
+    }
+    function.registers = allocation;
+}
+
+/// How much each scalar is used and each register clobbered in a function, weighted by loop nesting
+#[derive(Default)]
+struct Usage {
+    uses: HashMap<String, u64>,
+    clobbers: HashMap<Register, u64>,
+}
+
+impl Usage {
+    fn of(function: &Function) -> Usage {
+        let mut usage = Usage::default();
+        for (block, depth) in function.blocks.iter().zip(function.loop_depths()) {
+            let weight = LOOP_WEIGHT.saturating_pow(depth as u32);
+            for operation in &block.operations {
+                match operation {
+                    Operation::Load(_, Place::Variable(name)) | Operation::Store(Place::Variable(name), _) | Operation::Read(Place::Variable(name)) => {
+                        let uses = usage.uses.entry(name.clone()).or_default();
+                        *uses = uses.saturating_add(weight);
+                    },
+                    Operation::Binary(_, op, operand_0, operand_1) => {
+                        if let Some(routine) = routine_of(*op, operand_0, operand_1) {
+                            usage.clobber(clobbered_by(routine), weight);
+                        }
+                    },
+                    Operation::Call(_, _) => usage.clobber(&VARIABLE_REGISTERS, weight),
+                    Operation::Load(_, _) | Operation::Store(_, _) | Operation::Read(_) | Operation::Write(_) => {},
                 }
-                instructions.push(Instruction::Add(H));
-                Ok(instructions)
             }
         }
+        usage
     }
-    fn check_if_initialised(&self, value: Value) {
-        match value {
-            Value::Num(_) => {},
-            Value::Id(identifier) => {
-                let id = match identifier.clone() {
-                    Identifier::Base(id) => id,
-                    Identifier::NumIndexed(id, _) => id,
-                    Identifier::PidIndexed(id, _) => id,
-                };
-                if !self.initialisated_variables.contains(&id.0) {
-                    let id_for_warning = id.0.split('@').next().unwrap().to_string();
-                    println!("Warning: Variable {} used before initialisation", id_for_warning)
-                }
+    fn clobber(&mut self, registers: &[Register], weight: u64) {
+        for register in registers {
+            let clobbers = self.clobbers.entry(*register).or_default();
+            *clobbers = clobbers.saturating_add(weight);
+        }
+    }
+}
+
+/// Lays pseudo-assembly out: expands the macro instructions, gives every label the address of the instruction after it
+/// and makes the jumps absolute
+pub(crate) fn lay_out(pseudo_assembly: &[Instruction]) -> String {
+    let labels = pseudo_assembly.iter().filter_map(|instruction| match instruction {
+        Instruction::Label(label) => Some(label.0 + 1),
+        _ => None,
+    });
+    let mut next_label = labels.max().unwrap_or(0);
+    let code: Vec<Instruction> = pseudo_assembly.iter().flat_map(|instruction| expand(*instruction, &mut next_label)).collect();
+    let mut addresses: HashMap<Label, usize> = HashMap::new();
+    let mut address = 0;
+    for instruction in &code {
+        match instruction {
+            Instruction::Label(label) => {
+                addresses.insert(*label, address);
             },
+            _ => address += 1,
+        }
+    }
+    let mut assembled = String::new();
+    for instruction in &code {
+        match instruction {
+            Instruction::Label(_) => {},
+            Instruction::Jump(label) => assembled += &format!("JUMP {}\n", addresses[label]),
+            Instruction::Jpos(label) => assembled += &format!("JPOS {}\n", addresses[label]),
+            Instruction::Jzero(label) => assembled += &format!("JZERO {}\n", addresses[label]),
+            instruction => assembled += &format!("{instruction}\n"),
         }
     }
+    assembled
+}
+/// The instructions a macro instruction stands for, which take their operands in `B` and `C` and leave the result in `A`.
+/// Other instructions stand for themselves. The labels are numbered from `next_label` on.
+fn expand(instruction: Instruction, next_label: &mut usize) -> Vec<Instruction> {
+    let mut label = || {
+        *next_label += 1;
+        Label(*next_label - 1)
+    };
+    match instruction {
+        // Adds `B` shifted left for every bit of `C`
+        Instruction::Mul => {
+            let (next_bit, skip, end) = (label(), label(), label());
+            vec![
+                Instruction::Put(E),
+                Instruction::Rst(D),
+                Instruction::Label(next_bit),
+                Instruction::Get(C),
+                Instruction::Jzero(end),
+                Instruction::Shr(E),
+                Instruction::Shl(E),
+                Instruction::Get(C),
+                Instruction::Sub(E),
+                Instruction::Jzero(skip),
+                Instruction::Get(D),
+                Instruction::Add(B),
+                Instruction::Put(D),
+                Instruction::Label(skip),
+                Instruction::Shl(B),
+                Instruction::Shr(C),
+                Instruction::Get(C),
+                Instruction::Put(E),
+                Instruction::Jump(next_bit),
+                Instruction::Label(end),
+                Instruction::Get(D),
+            ]
+        },
+        // Long division, the quotient ends up in `D` and the remainder in `B`. Dividing by 0 gives 0.
+        Instruction::Div | Instruction::Mod => {
+            let (outer, inner, next, zero, end) = (label(), label(), label(), label(), label());
+            let mut instructions = vec![
+                Instruction::Rst(D),
+                Instruction::Jzero(zero),
+                Instruction::Label(outer),
+                Instruction::Get(C),
+                Instruction::Sub(B),
+                Instruction::Jpos(end),
+                Instruction::Get(C),
+                Instruction::Put(E),
+                Instruction::Rst(F),
+                Instruction::Inc(F),
+                Instruction::Label(inner),
+                Instruction::Get(E),
+                Instruction::Sub(B),
+                Instruction::Jpos(next),
+                Instruction::Get(B),
+                Instruction::Sub(E),
+                Instruction::Put(B),
+                Instruction::Get(D),
+                Instruction::Add(F),
+                Instruction::Put(D),
+                Instruction::Shl(E),
+                Instruction::Shl(F),
+                Instruction::Jump(inner),
+                Instruction::Label(next),
+                Instruction::Jump(outer),
+                Instruction::Label(zero),
+            ];
+            match instruction {
+                Instruction::Div => instructions.extend([Instruction::Label(end), Instruction::Get(D)]),
+                _ => instructions.extend([Instruction::Rst(B), Instruction::Label(end), Instruction::Get(B)]),
+            }
+            instructions
+        },
+        instruction => vec![instruction],
+    }
+}
+/// Whether the test of `relation` jumps when it holds, a `JPOS` can only tell the values apart
+fn jumps_if_true(relation: Relation) -> bool {
+    matches!(relation, Relation::NotEqual | Relation::Greater | Relation::Lower)
+}
+fn constant(operand: &Operand) -> Option<&Num> {
+    match operand {
+        Operand::Const(num) => Some(num),
+        Operand::Temp(_) => None,
+    }
+}
+/// The routine `operand_0 op operand_1` needs, `None` when constant operands allow shorter code: multiplying by a constant,
+/// dividing by a power of two, the remainder modulo a power of two up to 2^24, and anything with 0 as its first operand
+fn routine_of(op: BinaryOp, operand_0: &Operand, operand_1: &Operand) -> Option<Instruction> {
+    let (constant_0, constant_1) = (constant(operand_0), constant(operand_1));
+    if constant_0.is_some_and(|num| num.is_zero() || constant_1.is_some()) {
+        return None;
+    }
+    match (op, constant_1) {
+        (BinaryOp::Mul, None) if constant_0.is_none() => Some(Instruction::Mul),
+        (BinaryOp::Div, None) => Some(Instruction::Div),
+        (BinaryOp::Div, Some(num)) if num.count_ones() > 1 => Some(Instruction::Div),
+        (BinaryOp::Mod, None) => Some(Instruction::Mod),
+        (BinaryOp::Mod, Some(num)) if num.count_ones() > 1 || num.bits() > 25 => Some(Instruction::Mod),
+        _ => None,
+    }
+}
+/// Static cost of `instructions`, a use of a routine in `shared` paying for the call and the return
+fn static_cost(instructions: &[Instruction], shared: &[Instruction]) -> u64 {
+    let call_cost = |instruction: &Instruction| if shared.contains(instruction) { 5 } else { 0 };
+    instructions.iter().map(|instruction| instruction.cost() + call_cost(instruction)).sum()
+}
+/// Registers used as scratch by `routine`, one of `Mul`, `Div` and `Mod`
+fn clobbered_by(routine: Instruction) -> &'static [Register] {
+    match routine {
+        Instruction::Mul => &[D, E],
+        _ => &[D, E, F],
+    }
+}
+/// Puts the address of a scalar or the start of an array into the `A` register, following references.
+/// Scalars kept in registers only have their value there after a spill.
+fn get_address(variable: VariableVariant) -> Vec<Instruction> {
+    match variable {
+        VariableVariant::Atomic(pointer) | VariableVariant::Table(pointer) | VariableVariant::Register(_, pointer) => get_number(&Num::from(pointer)),
+        VariableVariant::Reference(slot) | VariableVariant::TableReference(slot) => {
+            let mut instructions = get_number(&Num::from(slot));
+            instructions.push(Instruction::Load(A));
+            instructions
+        },
+    }
 }
-/// Puts the `num` into the `A` register
-fn get_number(mut num: u64) -> Vec<Instruction> {
+/// Puts the `num` into the `A` register, going through its bits from the most significant one
+pub(crate) fn get_number(num: &Num) -> Vec<Instruction> {
     let mut instructions: Vec<Instruction> = Vec::new();
     instructions.push(Instruction::Rst(A));
-    if num != 0 {
+    let bits = num.bits();
+    if bits != 0 {
         instructions.push(Instruction::Inc(A));
-        let mut sub_instructions: Vec<Instruction> = Vec::new();
-        while num != 1 {
-            if num % 2 == 1 {
-                sub_instructions.push(Instruction::Inc(A));
-                num -= 1;
-            } else {
-                sub_instructions.push(Instruction::Shl(A));
-                num /= 2;
+        for bit in (0..bits - 1).rev() {
+            instructions.push(Instruction::Shl(A));
+            if num.bit(bit) {
+                instructions.push(Instruction::Inc(A));
             }
         }
-        sub_instructions.reverse();
-        instructions.extend(sub_instructions);
     }
     instructions
 }
@@ -1,37 +1,99 @@
-pub type Num = u64;
+use num_bigint::BigUint;
 
-pub type Pidentifier = (String, usize);
+/// Literals are unbounded, like the values the VM works on
+pub type Num = BigUint;
+
+/// Byte range of a node in the source, `end` is exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+}
+
+pub type Pidentifier = (String, Span);
 
 #[derive(Debug, Clone)]
 pub enum Identifier {
     Base(Pidentifier),
-    NumIndexed(Pidentifier, Num),
-    PidIndexed(Pidentifier, Pidentifier),
+    NumIndexed(Pidentifier, Num, Span),
+    PidIndexed(Pidentifier, Pidentifier, Span),
+}
+
+impl Identifier {
+    pub fn span(&self) -> Span {
+        match self {
+            Identifier::Base(id) => id.1,
+            Identifier::NumIndexed(_, _, span) => *span,
+            Identifier::PidIndexed(_, _, span) => *span,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum Value {
-    Num(Num),
+    Num(Num, Span),
     Id(Identifier),
 }
+
+impl Value {
+    pub fn span(&self) -> Span {
+        match self {
+            Value::Num(_, span) => *span,
+            Value::Id(identifier) => identifier.span(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Condition {
-    Equal(Value, Value),
-    NotEqual(Value, Value),
-    Greater(Value, Value),
-    Lower(Value, Value),
-    GreaterOrEqual(Value, Value),
-    LowerOrEqual(Value, Value),
+    Equal(Value, Value, Span),
+    NotEqual(Value, Value, Span),
+    Greater(Value, Value, Span),
+    Lower(Value, Value, Span),
+    GreaterOrEqual(Value, Value, Span),
+    LowerOrEqual(Value, Value, Span),
+}
+
+impl Condition {
+    pub fn span(&self) -> Span {
+        match self {
+            Condition::Equal(_, _, span)
+            | Condition::NotEqual(_, _, span)
+            | Condition::Greater(_, _, span)
+            | Condition::Lower(_, _, span)
+            | Condition::GreaterOrEqual(_, _, span)
+            | Condition::LowerOrEqual(_, _, span) => *span,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum Expression {
-    Val(Value),
-    Add(Value, Value),
-    Substract(Value, Value),
-    Multiply(Value, Value),
-    Divide(Value, Value),
-    Modulo(Value, Value),
+    Val(Value, Span),
+    Add(Value, Value, Span),
+    Substract(Value, Value, Span),
+    Multiply(Value, Value, Span),
+    Divide(Value, Value, Span),
+    Modulo(Value, Value, Span),
+}
+
+impl Expression {
+    pub fn span(&self) -> Span {
+        match self {
+            Expression::Val(_, span)
+            | Expression::Add(_, _, span)
+            | Expression::Substract(_, _, span)
+            | Expression::Multiply(_, _, span)
+            | Expression::Divide(_, _, span)
+            | Expression::Modulo(_, _, span) => *span,
+        }
+    }
 }
 
 pub type Arguments = Vec<Pidentifier>;
@@ -39,7 +101,16 @@ pub type Arguments = Vec<Pidentifier>;
 #[derive(Debug, Clone)]
 pub enum ArgumentsDeclarationVariant {
     Base(Pidentifier),
-    Table(Pidentifier),
+    Table(Pidentifier, Span),
+}
+
+impl ArgumentsDeclarationVariant {
+    pub fn span(&self) -> Span {
+        match self {
+            ArgumentsDeclarationVariant::Base(id) => id.1,
+            ArgumentsDeclarationVariant::Table(_, span) => *span,
+        }
+    }
 }
 
 pub type ArgumentsDeclaration = Vec<ArgumentsDeclarationVariant>;
@@ -47,31 +118,58 @@ pub type ArgumentsDeclaration = Vec<ArgumentsDeclarationVariant>;
 #[derive(Debug, Clone)]
 pub enum DeclarationVariant {
     Base(Pidentifier),
-    NumIndexed(Pidentifier, Num),
+    NumIndexed(Pidentifier, Num, Span),
+}
+
+impl DeclarationVariant {
+    pub fn span(&self) -> Span {
+        match self {
+            DeclarationVariant::Base(id) => id.1,
+            DeclarationVariant::NumIndexed(_, _, span) => *span,
+        }
+    }
 }
 
 pub type Declarations = Vec<DeclarationVariant>;
 
 pub type ProcedureCall = (Pidentifier, Arguments);
 
-pub type ProcedureHead = (Pidentifier, ArgumentsDeclaration);
+/// The span covers the name and the parenthesised parameters
+pub type ProcedureHead = (Pidentifier, ArgumentsDeclaration, Span);
 
 #[derive(Debug, Clone)]
 pub enum Command {
-    Assign(Identifier, Expression),
-    If(Condition, Commands, Option<Commands>),
-    While(Condition, Commands),
-    Repeat(Commands, Condition),
-    ProcCall(ProcedureCall),
-    Read(Identifier),
-    Write(Value),
+    Assign(Identifier, Expression, Span),
+    If(Condition, Commands, Option<Commands>, Span),
+    While(Condition, Commands, Span),
+    Repeat(Commands, Condition, Span),
+    ProcCall(ProcedureCall, Span),
+    Read(Identifier, Span),
+    Write(Value, Span),
+}
+
+impl Command {
+    /// Span of the whole command, from its first keyword to its terminator
+    pub fn span(&self) -> Span {
+        match self {
+            Command::Assign(_, _, span) => *span,
+            Command::If(_, _, _, span) => *span,
+            Command::While(_, _, span) => *span,
+            Command::Repeat(_, _, span) => *span,
+            Command::ProcCall(_, span) => *span,
+            Command::Read(_, span) => *span,
+            Command::Write(_, span) => *span,
+        }
+    }
 }
 
 pub type Commands = Vec<Command>;
 
-pub type Main = (Option<Declarations>, Commands);
+/// The span runs from `PROGRAM` to the final `END`
+pub type Main = (Option<Declarations>, Commands, Span);
 
-pub type Procedure = (ProcedureHead, Option<Declarations>, Commands);
+/// The span runs from `PROCEDURE` to its `END`
+pub type Procedure = (ProcedureHead, Option<Declarations>, Commands, Span);
 
 pub type Procedures = Vec<Procedure>;
 
@@ -0,0 +1,111 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::assembler::CompilerError;
+use crate::ast::*;
+
+/// Which procedures call which, with the procedures kept in declaration order
+#[derive(Debug, Clone, Default)]
+pub struct CallGraph {
+    procedures: Vec<String>,
+    name_spans: HashMap<String, Span>,
+    /// Callee and the span of the call, in the order the calls appear in the body
+    calls: HashMap<String, Vec<(String, Span)>>,
+}
+
+impl CallGraph {
+    pub fn new() -> CallGraph {
+        CallGraph::default()
+    }
+    /// Adds a procedure, which has to come after every procedure declared before it in the source
+    pub fn add_procedure(&mut self, name: &str, name_span: Span, commands: &Commands) {
+        let mut calls = Vec::new();
+        collect_calls(commands, &mut calls);
+        self.procedures.push(name.to_string());
+        self.name_spans.insert(name.to_string(), name_span);
+        self.calls.insert(name.to_string(), calls);
+    }
+    /// Procedures called directly by `name`, without repeats
+    pub fn callees(&self, name: &str) -> Vec<&str> {
+        let mut seen: HashSet<&str> = HashSet::new();
+        self.calls
+            .get(name)
+            .into_iter()
+            .flatten()
+            .map(|(callee, _)| callee.as_str())
+            .filter(|callee| seen.insert(callee))
+            .collect()
+    }
+    /// Whether any procedure calls `name`, calls in main are not recorded
+    pub fn is_called(&self, name: &str) -> bool {
+        self.calls.values().flatten().any(|(callee, _)| callee == name)
+    }
+    fn declaration_index(&self, name: &str) -> Option<usize> {
+        self.procedures.iter().position(|procedure| procedure == name)
+    }
+    /// Shortest chain of calls leading from `from` to `to`, both included
+    fn call_chain(&self, from: &str, to: &str) -> Option<Vec<String>> {
+        let mut previous: HashMap<&str, &str> = HashMap::new();
+        let mut queue: VecDeque<&str> = VecDeque::from([from]);
+        let mut visited: HashSet<&str> = HashSet::from([from]);
+        while let Some(procedure) = queue.pop_front() {
+            if procedure == to {
+                let mut chain = vec![to.to_string()];
+                let mut current = to;
+                while current != from {
+                    current = previous[current];
+                    chain.push(current.to_string());
+                }
+                chain.reverse();
+                return Some(chain);
+            }
+            for callee in self.callees(procedure) {
+                if visited.insert(callee) {
+                    previous.insert(callee, procedure);
+                    queue.push_back(callee);
+                }
+            }
+        }
+        None
+    }
+    /// A procedure may only call procedures declared before it.
+    /// A call breaking that rule is reported as recursive when it closes a cycle, with the chain of calls forming it.
+    pub fn check(&self) -> Vec<CompilerError> {
+        let mut errors = Vec::new();
+        for (caller_index, caller) in self.procedures.iter().enumerate() {
+            for (callee, call_span) in &self.calls[caller] {
+                // Calls to undeclared procedures are reported by `ir::lower`
+                let Some(callee_index) = self.declaration_index(callee) else {
+                    continue;
+                };
+                if callee_index < caller_index {
+                    continue;
+                }
+                match self.call_chain(callee, caller) {
+                    Some(chain) => {
+                        let mut cycle = vec![caller.clone()];
+                        cycle.extend(chain);
+                        errors.push(CompilerError::RecursiveProcedureCall(cycle, *call_span));
+                    },
+                    None => errors.push(CompilerError::ProcedureCalledBeforeDeclaration(callee.clone(), *call_span, self.name_spans[callee])),
+                }
+            }
+        }
+        errors
+    }
+}
+
+pub(crate) fn collect_calls(commands: &Commands, calls: &mut Vec<(String, Span)>) {
+    for command in commands {
+        match command {
+            Command::If(_, commands, else_commands, _) => {
+                collect_calls(commands, calls);
+                if let Some(else_commands) = else_commands {
+                    collect_calls(else_commands, calls);
+                }
+            },
+            Command::While(_, commands, _) | Command::Repeat(commands, _, _) => collect_calls(commands, calls),
+            Command::ProcCall((procedure_id, _), span) => calls.push((procedure_id.0.clone(), *span)),
+            Command::Assign(_, _, _) | Command::Read(_, _) | Command::Write(_, _) => {},
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use gembalang::diagnostics::WarningOptions;
+use gembalang::{CompileOptions, OptLevel};
+
+pub const USAGE: &str = "\
+Usage: kompilator [build] <input> [<output>] [options]
+       kompilator check <input> [options]
+       kompilator run <input> [options]
+       kompilator fmt <input> [-o <file> | --check] [options]
+
+Subcommands:
+  build        compile <input>, the default
+  check        only report errors and warnings, nothing is written
+  run          compile <input> and execute it, READ takes numbers from stdin
+  fmt          print <input> in canonical style, comments kept
+
+Input and output:
+  <input>                  source file, `-` reads it from stdin
+  -o <file>                where `build` and `fmt` write, `-` or no file at all means stdout
+  --emit=<kind>            what `build` writes: asm (default), listing, pseudo, ir, ast or ast-json
+  --check                  `fmt` writes nothing and fails if <input> is not formatted
+  --cost-report            print the static cost of every command, procedure and of the program to stderr
+  --print-after=<pass>     print the IR, or the pseudo-assembly for passes over the code, as it is after a pass to stderr, can be repeated
+
+Options:
+  --opt-level=<level>, -O<level>
+                           optimisation level: 0, 1, 2 (default) or s for short code
+  --passes=<pass>,...      run these passes instead of the ones of the level, see README.md
+  --disable-pass=<pass>    switches a pass off
+  --disable-peephole=<rule>
+                           switches a peephole rule off, see README.md
+  --error-format=<format>  human (default) or json
+  -W<category>, -Wno-<category>, -Werror=<category>, -Werror
+                           warning levels, see README.md
+  -h, --help               print this message
+  -V, --version            print the version
+
+Exit codes:
+  0  success
+  1  semantic errors, denied warnings included
+  2  syntax error
+  3  input or output could not be read or written
+  4  invalid command line
+  5  `run` failed while executing the program
+  6  `fmt --check` found <input> not formatted
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subcommand {
+    Build,
+    Check,
+    Run,
+    Fmt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Emit {
+    Asm,
+    Listing,
+    Pseudo,
+    /// The three-address code of `ir.rs`
+    Ir,
+    /// Only parses, then prints the AST as a tree
+    Ast,
+    /// Only parses, then prints the AST as JSON
+    AstJson,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Human,
+    Json,
+}
+
+/// A parsed command line that compiles something
+#[derive(Debug, Clone)]
+pub struct Cli {
+    pub subcommand: Subcommand,
+    /// `-` stands for stdin
+    pub input: String,
+    /// `None` and `-` stand for stdout
+    pub output: Option<String>,
+    pub emit: Emit,
+    /// `fmt --check`
+    pub check: bool,
+    pub cost_report: bool,
+    pub error_format: ErrorFormat,
+    pub options: CompileOptions,
+}
+
+#[derive(Debug, Clone)]
+pub enum Action {
+    Compile(Box<Cli>),
+    Help,
+    Version,
+}
+
+/// The `--error-format` in effect, so that errors in the rest of the command line are reported in it
+pub fn error_format(args: &[String]) -> ErrorFormat {
+    match args.iter().rev().find_map(|arg| arg.strip_prefix("--error-format=")) {
+        Some("json") => ErrorFormat::Json,
+        _ => ErrorFormat::Human,
+    }
+}
+
+/// Parses the arguments, without the program name. Errors are messages for the user.
+pub fn parse_args(args: &[String]) -> Result<Action, String> {
+    let mut subcommand: Option<Subcommand> = None;
+    let mut positionals: Vec<String> = Vec::new();
+    let mut output: Option<String> = None;
+    let mut emit: Option<Emit> = None;
+    let mut check = false;
+    let mut cost_report = false;
+    let mut error_format = ErrorFormat::Human;
+    let mut options = CompileOptions::default();
+    let mut warning_options = WarningOptions::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Action::Help),
+            "-V" | "--version" => return Ok(Action::Version),
+            "-o" => {
+                let file = args.next().ok_or("`-o` needs a file name")?;
+                if output.replace(file.clone()).is_some() {
+                    return Err("more than one output given".to_string());
+                }
+            },
+            "-" => positionals.push(arg.clone()),
+            "--check" => check = true,
+            "--cost-report" => cost_report = true,
+            "build" | "check" | "run" | "fmt" if subcommand.is_none() && positionals.is_empty() => {
+                subcommand = Some(match arg.as_str() {
+                    "build" => Subcommand::Build,
+                    "check" => Subcommand::Check,
+                    "run" => Subcommand::Run,
+                    _ => Subcommand::Fmt,
+                });
+            },
+            _ if arg.starts_with("-W") => warning_options.apply_flag(arg)?,
+            _ if arg.starts_with("--emit=") => {
+                emit = Some(match &arg["--emit=".len()..] {
+                    "asm" => Emit::Asm,
+                    "listing" => Emit::Listing,
+                    "pseudo" => Emit::Pseudo,
+                    "ir" => Emit::Ir,
+                    "ast" => Emit::Ast,
+                    "ast-json" => Emit::AstJson,
+                    other => return Err(format!("unknown output kind `{other}`, expected `asm`, `listing`, `pseudo`, `ir`, `ast` or `ast-json`")),
+                });
+            },
+            _ if arg.starts_with("--opt-level=") || arg.starts_with("-O") => {
+                let level = arg.strip_prefix("--opt-level=").or_else(|| arg.strip_prefix("-O")).unwrap_or_default();
+                options.opt_level = OptLevel::from_name(level)
+                    .ok_or_else(|| format!("unknown optimisation level `{level}`, expected `0`, `1`, `2` or `s`"))?;
+            },
+            _ if arg.starts_with("--passes=") => options.passes.set_passes(&arg["--passes=".len()..])?,
+            _ if arg.starts_with("--disable-pass=") => options.passes.disable(&arg["--disable-pass=".len()..])?,
+            _ if arg.starts_with("--print-after=") => options.passes.print_after(&arg["--print-after=".len()..])?,
+            _ if arg.starts_with("--disable-peephole=") => options.peephole.disable(&arg["--disable-peephole=".len()..])?,
+            _ if arg.starts_with("--error-format=") => {
+                error_format = match &arg["--error-format=".len()..] {
+                    "human" => ErrorFormat::Human,
+                    "json" => ErrorFormat::Json,
+                    other => return Err(format!("unknown error format `{other}`, expected `human` or `json`")),
+                };
+            },
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{arg}`")),
+            _ => positionals.push(arg.clone()),
+        }
+    }
+    options.warnings = warning_options;
+    let subcommand = subcommand.unwrap_or(Subcommand::Build);
+    let mut positionals = positionals.into_iter();
+    let input = positionals.next().ok_or("no input file given")?;
+    if let Some(positional_output) = positionals.next() {
+        if subcommand != Subcommand::Build {
+            return Err(format!("unexpected argument `{positional_output}`"));
+        }
+        if output.replace(positional_output).is_some() {
+            return Err("more than one output given".to_string());
+        }
+    }
+    if let Some(extra) = positionals.next() {
+        return Err(format!("unexpected argument `{extra}`"));
+    }
+    if matches!(subcommand, Subcommand::Check | Subcommand::Run) && (output.is_some() || emit.is_some()) {
+        return Err("only `build` and `fmt` write output, `-o` and `--emit` do not go with `check` or `run`".to_string());
+    }
+    if subcommand == Subcommand::Fmt && emit.is_some() {
+        return Err("`fmt` always writes source, `--emit` does not go with it".to_string());
+    }
+    if check && (subcommand != Subcommand::Fmt || output.is_some()) {
+        return Err("`--check` only goes with `fmt`, which then writes nothing".to_string());
+    }
+    if cost_report && (subcommand == Subcommand::Fmt || matches!(emit, Some(Emit::Ast | Emit::AstJson))) {
+        return Err("`--cost-report` needs the program compiled, it does not go with `fmt` or with `--emit=ast`".to_string());
+    }
+    if !options.passes.printed_after().is_empty() {
+        if subcommand == Subcommand::Fmt || matches!(emit, Some(Emit::Ast | Emit::AstJson)) {
+            return Err("`--print-after` needs the program compiled, it does not go with `fmt` or with `--emit=ast`".to_string());
+        }
+        let pipeline = options.passes.pipeline(options.opt_level);
+        if let Some(pass) = options.passes.printed_after().iter().find(|pass| !pipeline.runs(**pass)) {
+            return Err(format!("`--print-after={}` names a pass that does not run with these options", pass.name()));
+        }
+    }
+    if subcommand == Subcommand::Run && input == "-" {
+        return Err("`run` reads the program's input from stdin, so the source has to come from a file".to_string());
+    }
+    Ok(Action::Compile(Box::new(Cli {
+        subcommand,
+        input,
+        output,
+        emit: emit.unwrap_or(Emit::Asm),
+        check,
+        cost_report,
+        error_format,
+        options,
+    })))
+}
@@ -0,0 +1,400 @@
+use std::fmt::Write;
+
+use lalrpop_util::{lexer::Token, ParseError};
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+
+use crate::assembler::{CompilerError, CompilerWarning, WarningCategory};
+use crate::ast::Span;
+
+/// Collects every error found during a compilation so that all of them can be reported at once
+#[derive(Debug, Default)]
+pub struct DiagnosticSink {
+    errors: Vec<CompilerError>,
+    warnings: Vec<CompilerWarning>,
+}
+
+impl DiagnosticSink {
+    pub fn new() -> DiagnosticSink {
+        DiagnosticSink { errors: vec![], warnings: vec![] }
+    }
+    pub fn push(&mut self, error: CompilerError) {
+        self.errors.push(error);
+    }
+    pub fn push_warning(&mut self, warning: CompilerWarning) {
+        self.warnings.push(warning);
+    }
+    /// Removes the warnings, ordered by position and without repetitions
+    pub fn take_warnings(&mut self) -> Vec<CompilerWarning> {
+        let mut warnings: Vec<CompilerWarning> = Vec::new();
+        for warning in std::mem::take(&mut self.warnings) {
+            if !warnings.contains(&warning) {
+                warnings.push(warning);
+            }
+        }
+        warnings.sort_by_key(|warning| warning.get_span().start);
+        warnings
+    }
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty()
+    }
+    /// Returns the errors ordered by their position in the source.
+    /// Procedure bodies can be built more than once, so the same error can be found more than once.
+    pub fn into_sorted(self) -> Vec<CompilerError> {
+        let mut errors: Vec<CompilerError> = Vec::new();
+        for error in self.errors {
+            if !errors.contains(&error) {
+                errors.push(error);
+            }
+        }
+        errors.sort_by_key(|error| error.get_span().map(|span| span.start));
+        errors
+    }
+}
+
+/// What to do with warnings of a given category
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarningLevel {
+    Allow,
+    Warn,
+    Deny,
+}
+
+/// Per-category warning levels, set with `-W<category>`, `-Wno-<category>`, `-Werror=<category>` and `-Werror`
+#[derive(Debug, Clone, Default)]
+pub struct WarningOptions {
+    levels: HashMap<WarningCategory, WarningLevel>,
+    warnings_are_errors: bool,
+}
+
+impl WarningOptions {
+    pub fn new() -> WarningOptions {
+        WarningOptions::default()
+    }
+    /// Applies a single `-W...` flag, returns an error message for unknown flags or categories
+    pub fn apply_flag(&mut self, flag: &str) -> Result<(), String> {
+        let Some(flag) = flag.strip_prefix("-W") else {
+            return Err(format!("`{flag}` is not a warning flag"));
+        };
+        if flag == "error" {
+            self.warnings_are_errors = true;
+            return Ok(());
+        }
+        let (name, level) = if let Some(name) = flag.strip_prefix("error=") {
+            (name, WarningLevel::Deny)
+        } else if let Some(name) = flag.strip_prefix("no-") {
+            (name, WarningLevel::Allow)
+        } else {
+            (flag, WarningLevel::Warn)
+        };
+        let category = WarningCategory::from_name(name).ok_or_else(|| {
+            let known: Vec<&str> = WarningCategory::ALL.iter().map(|category| category.name()).collect();
+            format!("unknown warning category `{name}`, expected one of: {}", known.join(", "))
+        })?;
+        self.levels.insert(category, level);
+        Ok(())
+    }
+    /// Every category warns by default, `-Werror` promotes the ones that are not allowed
+    pub fn level(&self, category: WarningCategory) -> WarningLevel {
+        match self.levels.get(&category).copied().unwrap_or(WarningLevel::Warn) {
+            WarningLevel::Warn if self.warnings_are_errors => WarningLevel::Deny,
+            level => level,
+        }
+    }
+}
+
+/// Additional information attached to a diagnostic, optionally pointing somewhere else in the source
+#[derive(Debug, Clone)]
+pub struct Note {
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// A message ready to be shown to the user.
+/// `code` is stable across changes in wording, so tools should match on it rather than on `message`.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    /// `None` when the diagnostic is not about a place in the source
+    pub span: Option<Span>,
+    pub notes: Vec<Note>,
+}
+
+impl Diagnostic {
+    fn new(code: &'static str, message: String, span: Span) -> Diagnostic {
+        Diagnostic { severity: Severity::Error, code, message, span: Some(span), notes: vec![] }
+    }
+    fn warning(code: &'static str, message: String, span: Span) -> Diagnostic {
+        Diagnostic { severity: Severity::Warning, code, message, span: Some(span), notes: vec![] }
+    }
+    fn without_location(code: &'static str, message: String) -> Diagnostic {
+        Diagnostic { severity: Severity::Error, code, message, span: None, notes: vec![] }
+    }
+    fn with_note(mut self, message: String, span: Option<Span>) -> Diagnostic {
+        self.notes.push(Note { message, span });
+        self
+    }
+    pub fn from_compiler_error(error: &CompilerError) -> Diagnostic {
+        // Names inside procedures are stored as `name@procedure`
+        let name = |id: &String| id.split('@').next().unwrap_or_default().to_string();
+        match error {
+            CompilerError::UndeclaredVariable(id, span) => {
+                Diagnostic::new("E0101", format!("undeclared variable `{}`", name(id)), *span)
+            },
+            CompilerError::UndeclaredProcedure(id, span) => {
+                Diagnostic::new("E0102", format!("undeclared procedure `{id}`"), *span)
+            },
+            CompilerError::IncorrectUseOfVariable(id, span) => {
+                Diagnostic::new("E0103", format!("incorrect use of variable `{}`", name(id)), *span)
+                    .with_note("arrays have to be indexed and scalars cannot be".to_string(), None)
+            },
+            CompilerError::IndexOutOfBounds(id, span) => {
+                Diagnostic::new("E0104", format!("index out of bounds for array `{}`", name(id)), *span)
+            },
+            CompilerError::ArrayUsedAsIndex(id, span) => {
+                Diagnostic::new("E0105", format!("array `{}` used as an index", name(id)), *span)
+            },
+            CompilerError::WrongArgumentType(id, span, declaration) => {
+                Diagnostic::new("E0106", format!("wrong argument type for parameter `{}`", name(id)), *span)
+                    .with_note(format!("parameter `{}` declared here", name(id)), Some(*declaration))
+            },
+            CompilerError::DuplicateVariableDeclaration(id, span, first) => {
+                Diagnostic::new("E0107", format!("duplicate declaration of variable `{}`", name(id)), *span)
+                    .with_note(format!("`{}` first declared here", name(id)), Some(*first))
+            },
+            CompilerError::DuplicateProcedureDeclaration(id, span, first) => {
+                Diagnostic::new("E0108", format!("duplicate declaration of procedure `{id}`"), *span)
+                    .with_note(format!("`{id}` first declared here"), Some(*first))
+            },
+            CompilerError::RecursiveProcedureCall(chain, span) => {
+                let chain_text = chain.iter().map(|procedure| format!("`{procedure}`")).collect::<Vec<String>>().join(" -> ");
+                let callee = chain.get(1).cloned().unwrap_or_default();
+                Diagnostic::new("E0109", format!("recursive call of procedure `{callee}`"), *span)
+                    .with_note(format!("the calls form a cycle: {chain_text}"), None)
+            },
+            CompilerError::ProcedureCalledBeforeDeclaration(id, span, declaration) => {
+                Diagnostic::new("E0113", format!("procedure `{id}` is called before its declaration"), *span)
+                    .with_note(format!("`{id}` declared here"), Some(*declaration))
+            },
+            CompilerError::WrongNumberOfArguments(id, span, expected, head) => {
+                let parameters = if *expected == 1 { "parameter" } else { "parameters" };
+                Diagnostic::new("E0110", format!("wrong number of arguments for procedure `{id}`"), *span)
+                    .with_note(format!("procedure `{id}` declared here with {expected} {parameters}"), Some(*head))
+            },
+            CompilerError::ArrayTooLarge(id, span) => {
+                Diagnostic::new("E0111", format!("array `{}` does not fit in memory", name(id)), *span)
+                    .with_note("the whole memory has 2^64 cells".to_string(), None)
+            },
+            CompilerError::OutOfMemory(id, span) => {
+                Diagnostic::new("E0112", format!("variable `{}` does not fit in memory", name(id)), *span)
+                    .with_note("the whole memory has 2^64 cells".to_string(), None)
+            },
+            CompilerError::InvalidCommandLine(message) => {
+                Diagnostic::without_location("E0201", message.clone())
+                    .with_note("run `kompilator --help` for usage".to_string(), None)
+            },
+            CompilerError::UnreadableFile(path, reason) => {
+                Diagnostic::without_location("E0202", format!("cannot read `{path}`: {reason}"))
+            },
+            CompilerError::UnwritableFile(path, reason) => {
+                Diagnostic::without_location("E0203", format!("cannot write `{path}`: {reason}"))
+            },
+            CompilerError::NotFormatted(path) => Diagnostic::without_location("E0204", format!("`{path}` is not formatted"))
+                .with_note("run `kompilator fmt` without `--check` to format it".to_string(), None),
+            CompilerError::FormattingChangedProgram => {
+                Diagnostic::without_location("E0205", "formatting would change the program, nothing was written".to_string())
+                    .with_note("this is a bug in the formatter".to_string(), None)
+            },
+        }
+    }
+    /// Returns `None` for warnings that are allowed, denied ones become errors
+    pub fn from_compiler_warning(warning: &CompilerWarning, options: &WarningOptions) -> Option<Diagnostic> {
+        let name = |id: &String| id.split('@').next().unwrap_or_default().to_string();
+        let mut diagnostic = match warning {
+            CompilerWarning::UsedBeforeInitialisation(id, span) => {
+                Diagnostic::warning("W0001", format!("variable `{}` used before initialisation", name(id)), *span)
+            },
+            CompilerWarning::PossiblyUsedBeforeInitialisation(id, span) => {
+                Diagnostic::warning("W0004", format!("variable `{}` is possibly used before initialisation", name(id)), *span)
+                    .with_note("it is not assigned on every path leading here".to_string(), None)
+            },
+            CompilerWarning::UnusedVariable(id, span) => {
+                Diagnostic::warning("W0002", format!("unused variable `{}`", name(id)), *span)
+            },
+            CompilerWarning::UnusedProcedure(id, span) => {
+                Diagnostic::warning("W0003", format!("procedure `{id}` is never called"), *span)
+            },
+        };
+        let category = warning.category();
+        match options.level(category) {
+            WarningLevel::Allow => return None,
+            WarningLevel::Warn => {},
+            WarningLevel::Deny => {
+                diagnostic.severity = Severity::Error;
+                diagnostic = diagnostic.with_note(format!("denied by `-Werror` or `-Werror={}`", category.name()), None);
+            },
+        }
+        Some(diagnostic)
+    }
+    pub fn from_parse_error(error: &ParseError<usize, Token<'_>, &'static str>, source: &str) -> Diagnostic {
+        match error {
+            ParseError::InvalidToken { location } => {
+                let found = source[*location..].chars().next().unwrap_or(' ');
+                Diagnostic::new("E0001", format!("invalid token `{found}`"), Span::new(*location, *location + found.len_utf8()))
+            },
+            ParseError::UnrecognizedEof { location, expected } => {
+                Diagnostic::new("E0002", "unexpected end of file".to_string(), Span::new(*location, *location))
+                    .with_note(format!("expected one of: {}", format_expected(expected)), None)
+            },
+            ParseError::UnrecognizedToken { token: (start, token, end), expected } => {
+                Diagnostic::new("E0003", format!("unexpected token `{token}`"), Span::new(*start, *end))
+                    .with_note(format!("expected one of: {}", format_expected(expected)), None)
+            },
+            ParseError::ExtraToken { token: (start, token, end) } => {
+                Diagnostic::new("E0004", format!("extra token `{token}`"), Span::new(*start, *end))
+            },
+            ParseError::User { error } => Diagnostic::new("E0005", error.to_string(), Span::new(0, 0)),
+        }
+    }
+}
+
+/// Turns the grammar's terminal names into something readable, e.g. `r#"[0-9]+"#` into `number`
+fn format_expected(expected: &[String]) -> String {
+    expected
+        .iter()
+        .map(|terminal| match terminal.as_str() {
+            r##"r#"[0-9]+"#"## => "number".to_string(),
+            r##"r#"[_a-z]+"#"## => "identifier".to_string(),
+            _ => terminal.trim_matches('"').to_string(),
+        })
+        .map(|terminal| format!("`{terminal}`"))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// A source file with its line starts, for turning byte offsets into lines and columns
+pub struct SourceFile<'a> {
+    name: &'a str,
+    source: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> SourceFile<'a> {
+    pub fn new(name: &'a str, source: &'a str) -> SourceFile<'a> {
+        let mut line_starts = vec![0];
+        line_starts.extend(source.match_indices('\n').map(|(i, _)| i + 1));
+        SourceFile { name, source, line_starts }
+    }
+    /// 1-based line and column of `byte`, columns count characters
+    pub fn line_and_column(&self, byte: usize) -> (usize, usize) {
+        let byte = byte.min(self.source.len());
+        let line = self.line_starts.partition_point(|start| *start <= byte);
+        let line_start = self.line_starts[line - 1];
+        let column = self.source[line_start..byte].chars().count() + 1;
+        (line, column)
+    }
+    /// Text of the 1-based `line` without its line terminator, `\r\n` included
+    fn line_text(&self, line: usize) -> &'a str {
+        let start = self.line_starts[line - 1];
+        let end = self.line_starts.get(line).copied().unwrap_or(self.source.len());
+        self.source[start..end].trim_end_matches(['\n', '\r'])
+    }
+}
+
+/// Renders the diagnostic the way rustc does: location, the offending line and carets under the span
+pub fn render(diagnostic: &Diagnostic, file: &SourceFile, colour: bool) -> String {
+    let paint = |text: &str, code: &str| if colour { format!("\x1b[{code}m{text}\x1b[0m") } else { text.to_string() };
+    let mut rendered = String::new();
+    let severity_colour = match diagnostic.severity {
+        Severity::Error => "1;31",
+        Severity::Warning => "1;33",
+    };
+    let header = format!("{}[{}]", diagnostic.severity.as_str(), diagnostic.code);
+    let _ = writeln!(rendered, "{}{}", paint(&header, severity_colour), paint(&format!(": {}", diagnostic.message), "1"));
+    if let Some(span) = diagnostic.span {
+        render_snippet(&mut rendered, span, '^', severity_colour, file, &paint);
+    }
+    for note in &diagnostic.notes {
+        match note.span {
+            Some(span) => {
+                let _ = writeln!(rendered, "{}: {}", paint("note", "1;36"), note.message);
+                render_snippet(&mut rendered, span, '-', "1;36", file, &paint);
+            },
+            None => {
+                let _ = writeln!(rendered, "  {} note: {}", paint("=", "1;34"), note.message);
+            },
+        }
+    }
+    rendered
+}
+
+fn render_snippet(rendered: &mut String, span: Span, marker: char, code: &str, file: &SourceFile, paint: &dyn Fn(&str, &str) -> String) {
+    let (line, column) = file.line_and_column(span.start);
+    let text = file.line_text(line);
+    let gutter = " ".repeat(line.to_string().len());
+    // Spans over several lines are underlined up to the end of their first line
+    let line_end_column = text.chars().count() + 1;
+    let (end_line, end_column) = file.line_and_column(span.end);
+    let end_column = if end_line == line { end_column } else { line_end_column };
+    let width = end_column.saturating_sub(column).max(1);
+    let _ = writeln!(rendered, "{gutter}{} {}:{line}:{column}", paint("-->", "1;34"), file.name);
+    let _ = writeln!(rendered, "{gutter} {}", paint("|", "1;34"));
+    let _ = writeln!(rendered, "{} {text}", paint(&format!("{line} |"), "1;34"));
+    let underline: String = marker.to_string().repeat(width);
+    // Tabs are kept so the carets line up with the text above
+    let indent: String = text.chars().take(column - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+    let _ = writeln!(rendered, "{gutter} {} {indent}{}", paint("|", "1;34"), paint(&underline, code));
+}
+
+/// Renders the diagnostic as a single line of JSON, for tools that would otherwise scrape the human output
+pub fn render_json(diagnostic: &Diagnostic, file: &SourceFile) -> String {
+    // The fields giving the place of `span`, null without one
+    let location = |span: Option<Span>| match span {
+        Some(span) => {
+            let (line, column) = file.line_and_column(span.start);
+            (json!({ "start": span.start, "end": span.end }), json!(line), json!(column))
+        },
+        None => (Value::Null, Value::Null, Value::Null),
+    };
+    let notes: Vec<Value> = diagnostic
+        .notes
+        .iter()
+        .map(|note| match note.span {
+            Some(_) => {
+                let (span, line, column) = location(note.span);
+                json!({ "message": note.message, "span": span, "line": line, "column": column })
+            },
+            None => json!({ "message": note.message }),
+        })
+        .collect();
+    let (span, line, column) = location(diagnostic.span);
+    json!({
+        "severity": diagnostic.severity.as_str(),
+        "code": diagnostic.code,
+        "message": diagnostic.message,
+        "file": file.name,
+        "span": span,
+        "line": line,
+        "column": column,
+        "notes": notes,
+    })
+    .to_string()
+}
@@ -0,0 +1,240 @@
+use std::fmt::Write;
+
+use serde_json::json;
+
+use crate::ast::*;
+
+/// Just enough of JSON to describe the AST, rendered both as JSON and as an indented tree
+enum Node {
+    Null,
+    Text(String),
+    Span(Span),
+    List(Vec<Node>),
+    /// Objects describing an AST node have a `kind` first
+    Object(Vec<(&'static str, Node)>),
+}
+
+fn object(kind: &str, fields: Vec<(&'static str, Node)>) -> Node {
+    let mut all_fields = vec![("kind", Node::Text(kind.to_string()))];
+    all_fields.extend(fields);
+    Node::Object(all_fields)
+}
+
+fn pidentifier(id: &Pidentifier) -> Node {
+    Node::Object(vec![("name", Node::Text(id.0.clone())), ("span", Node::Span(id.1))])
+}
+
+fn identifier(identifier: &Identifier) -> Node {
+    match identifier {
+        Identifier::Base(id) => object("variable", vec![("name", Node::Text(id.0.clone())), ("span", Node::Span(id.1))]),
+        Identifier::NumIndexed(id, index, span) => object(
+            "element",
+            vec![("name", Node::Text(id.0.clone())), ("index", Node::Text(index.to_string())), ("span", Node::Span(*span))],
+        ),
+        Identifier::PidIndexed(id, index, span) => object(
+            "element",
+            vec![("name", Node::Text(id.0.clone())), ("index", pidentifier(index)), ("span", Node::Span(*span))],
+        ),
+    }
+}
+
+fn value(value: &Value) -> Node {
+    match value {
+        Value::Num(num, span) => object("number", vec![("value", Node::Text(num.to_string())), ("span", Node::Span(*span))]),
+        Value::Id(id) => identifier(id),
+    }
+}
+
+fn expression(expression: &Expression) -> Node {
+    let (kind, value_0, value_1) = match expression {
+        Expression::Val(value_0, _) => return value(value_0),
+        Expression::Add(value_0, value_1, _) => ("add", value_0, value_1),
+        Expression::Substract(value_0, value_1, _) => ("subtract", value_0, value_1),
+        Expression::Multiply(value_0, value_1, _) => ("multiply", value_0, value_1),
+        Expression::Divide(value_0, value_1, _) => ("divide", value_0, value_1),
+        Expression::Modulo(value_0, value_1, _) => ("modulo", value_0, value_1),
+    };
+    object(kind, vec![("left", value(value_0)), ("right", value(value_1)), ("span", Node::Span(expression.span()))])
+}
+
+fn condition(condition: &Condition) -> Node {
+    let (kind, value_0, value_1) = match condition {
+        Condition::Equal(value_0, value_1, _) => ("equal", value_0, value_1),
+        Condition::NotEqual(value_0, value_1, _) => ("not_equal", value_0, value_1),
+        Condition::Greater(value_0, value_1, _) => ("greater", value_0, value_1),
+        Condition::Lower(value_0, value_1, _) => ("lower", value_0, value_1),
+        Condition::GreaterOrEqual(value_0, value_1, _) => ("greater_or_equal", value_0, value_1),
+        Condition::LowerOrEqual(value_0, value_1, _) => ("lower_or_equal", value_0, value_1),
+    };
+    object(kind, vec![("left", value(value_0)), ("right", value(value_1)), ("span", Node::Span(condition.span()))])
+}
+
+fn commands(commands: &Commands) -> Node {
+    Node::List(commands.iter().map(command).collect())
+}
+
+fn command(command: &Command) -> Node {
+    match command {
+        Command::Assign(target, assigned, span) => {
+            object("assign", vec![("target", identifier(target)), ("expression", expression(assigned)), ("span", Node::Span(*span))])
+        },
+        Command::If(if_condition, then_commands, else_commands, span) => object(
+            "if",
+            vec![
+                ("condition", condition(if_condition)),
+                ("then", commands(then_commands)),
+                ("else", else_commands.as_ref().map(commands).unwrap_or(Node::Null)),
+                ("span", Node::Span(*span)),
+            ],
+        ),
+        Command::While(while_condition, body, span) => {
+            object("while", vec![("condition", condition(while_condition)), ("body", commands(body)), ("span", Node::Span(*span))])
+        },
+        Command::Repeat(body, until_condition, span) => {
+            object("repeat", vec![("body", commands(body)), ("condition", condition(until_condition)), ("span", Node::Span(*span))])
+        },
+        Command::ProcCall((procedure, arguments), span) => object(
+            "call",
+            vec![
+                ("procedure", pidentifier(procedure)),
+                ("arguments", Node::List(arguments.iter().map(pidentifier).collect())),
+                ("span", Node::Span(*span)),
+            ],
+        ),
+        Command::Read(target, span) => object("read", vec![("target", identifier(target)), ("span", Node::Span(*span))]),
+        Command::Write(written, span) => object("write", vec![("value", value(written)), ("span", Node::Span(*span))]),
+    }
+}
+
+fn declarations(declarations: &Option<Declarations>) -> Node {
+    Node::List(
+        declarations
+            .iter()
+            .flatten()
+            .map(|declaration| match declaration {
+                DeclarationVariant::Base(id) => object("scalar", vec![("name", Node::Text(id.0.clone())), ("span", Node::Span(id.1))]),
+                DeclarationVariant::NumIndexed(id, size, span) => object(
+                    "array",
+                    vec![("name", Node::Text(id.0.clone())), ("size", Node::Text(size.to_string())), ("span", Node::Span(*span))],
+                ),
+            })
+            .collect(),
+    )
+}
+
+fn procedure(procedure: &Procedure) -> Node {
+    let ((name, parameters, head_span), procedure_declarations, body, span) = procedure;
+    let parameters = parameters
+        .iter()
+        .map(|parameter| match parameter {
+            ArgumentsDeclarationVariant::Base(id) => object("scalar", vec![("name", Node::Text(id.0.clone())), ("span", Node::Span(id.1))]),
+            ArgumentsDeclarationVariant::Table(id, span) => {
+                object("array", vec![("name", Node::Text(id.0.clone())), ("span", Node::Span(*span))])
+            },
+        })
+        .collect();
+    object(
+        "procedure",
+        vec![
+            ("name", Node::Text(name.0.clone())),
+            ("name_span", Node::Span(name.1)),
+            ("parameters", Node::List(parameters)),
+            ("head_span", Node::Span(*head_span)),
+            ("declarations", declarations(procedure_declarations)),
+            ("commands", commands(body)),
+            ("span", Node::Span(*span)),
+        ],
+    )
+}
+
+fn program(program: &Program) -> Node {
+    let (procedures, (main_declarations, main_commands, main_span)) = program;
+    object(
+        "program",
+        vec![
+            ("procedures", Node::List(procedures.iter().flatten().map(procedure).collect())),
+            (
+                "main",
+                object(
+                    "main",
+                    vec![
+                        ("declarations", declarations(main_declarations)),
+                        ("commands", commands(main_commands)),
+                        ("span", Node::Span(*main_span)),
+                    ],
+                ),
+            ),
+        ],
+    )
+}
+
+fn to_json(node: &Node) -> serde_json::Value {
+    match node {
+        Node::Null => serde_json::Value::Null,
+        Node::Text(text) => json!(text),
+        Node::Span(span) => json!({ "start": span.start, "end": span.end }),
+        Node::List(nodes) => serde_json::Value::Array(nodes.iter().map(to_json).collect()),
+        Node::Object(fields) => serde_json::Value::Object(fields.iter().map(|(key, node)| (key.to_string(), to_json(node))).collect()),
+    }
+}
+
+/// One line per object, with its kind, its text fields and its span. Lists and objects inside go below, indented.
+fn write_tree(node: &Node, label: Option<&str>, depth: usize, spans: bool, tree: &mut String) {
+    let indent = "  ".repeat(depth);
+    let label = label.map(|label| format!("{label}: ")).unwrap_or_default();
+    match node {
+        Node::Null => {
+            let _ = writeln!(tree, "{indent}{label}none");
+        },
+        Node::Text(text) => {
+            let _ = writeln!(tree, "{indent}{label}{text}");
+        },
+        Node::Span(_) if !spans => {},
+        Node::Span(span) => {
+            let _ = writeln!(tree, "{indent}{label}@{}..{}", span.start, span.end);
+        },
+        Node::List(nodes) => {
+            let _ = writeln!(tree, "{indent}{label}[{}]", nodes.len());
+            for node in nodes {
+                write_tree(node, None, depth + 1, spans, tree);
+            }
+        },
+        Node::Object(fields) => {
+            let mut line: Vec<String> = Vec::new();
+            let mut children: Vec<(&str, &Node)> = Vec::new();
+            for (key, field) in fields {
+                match (key, field) {
+                    (&"kind", Node::Text(kind)) => line.insert(0, kind.clone()),
+                    (&"span", Node::Span(span)) if spans => line.push(format!("@{}..{}", span.start, span.end)),
+                    (_, Node::Span(_)) if !spans => {},
+                    (_, Node::Text(text)) => line.push(format!("{key}={text}")),
+                    (_, Node::Null) => {},
+                    _ => children.push((key, field)),
+                }
+            }
+            let _ = writeln!(tree, "{indent}{label}{}", line.join(" "));
+            for (key, child) in children {
+                write_tree(child, Some(key), depth + 1, spans, tree);
+            }
+        },
+    }
+}
+
+/// The AST as one line of JSON. Numbers are strings, as they can be larger than JSON readers handle.
+pub fn ast_json(ast: &Program) -> String {
+    format!("{}\n", to_json(&program(ast)))
+}
+
+/// The AST as an indented tree, for reading
+pub fn ast_tree(ast: &Program) -> String {
+    let mut tree = String::new();
+    write_tree(&program(ast), None, 0, true, &mut tree);
+    tree
+}
+
+/// The tree without spans, equal for two programs that differ only in layout
+pub fn ast_shape(ast: &Program) -> String {
+    let mut shape = String::new();
+    write_tree(&program(ast), None, 0, false, &mut shape);
+    shape
+}
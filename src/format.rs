@@ -0,0 +1,281 @@
+use crate::assembler::CompilerError;
+use crate::ast::*;
+use crate::diagnostics::Diagnostic;
+use crate::dump::ast_shape;
+use crate::parse;
+
+const INDENT: &str = "  ";
+
+/// A `# comment`, the lexer skips them so they are found by scanning the source
+struct Comment {
+    start: usize,
+    end: usize,
+    text: String,
+}
+
+/// `#` cannot appear anywhere else in a program, so every `#` starts a comment running to the end of its line
+fn collect_comments(source: &str) -> Vec<Comment> {
+    let mut comments = Vec::new();
+    let mut position = 0;
+    while let Some(offset) = source[position..].find('#') {
+        let start = position + offset;
+        let end = source[start..].find(['\n', '\r']).map(|length| start + length).unwrap_or(source.len());
+        comments.push(Comment { start, end, text: source[start..end].trim_end().to_string() });
+        position = end;
+    }
+    comments
+}
+
+/// Position of `keyword` in `source[from..to]`, comments skipped
+fn find_keyword(source: &str, from: usize, to: usize, keyword: &str) -> usize {
+    let mut position = from;
+    while position < to {
+        let rest = &source[position..to];
+        if rest.starts_with('#') {
+            position += rest.find(['\n', '\r']).unwrap_or(rest.len());
+        } else if rest.starts_with(keyword) {
+            return position;
+        } else {
+            position += rest.chars().next().map(char::len_utf8).unwrap_or(1);
+        }
+    }
+    from
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    comments: Vec<Comment>,
+    next_comment: usize,
+    output: String,
+    depth: usize,
+    /// End in the source of the last thing written
+    last_end: usize,
+    /// No blank lines right after a line opening a block
+    at_block_start: bool,
+}
+
+impl<'a> Formatter<'a> {
+    fn new(source: &'a str) -> Formatter<'a> {
+        Formatter {
+            source,
+            comments: collect_comments(source),
+            next_comment: 0,
+            output: String::new(),
+            depth: 0,
+            last_end: 0,
+            at_block_start: true,
+        }
+    }
+    /// Keeps one blank line where the source has at least one
+    fn blank_line_before(&mut self, start: usize) {
+        let gap = self.source.get(self.last_end..start).unwrap_or_default();
+        if !self.at_block_start && gap.matches('\n').count() >= 2 {
+            self.output.push('\n');
+        }
+    }
+    /// Writes the comments that start before `before`, each on its own line
+    fn flush_comments(&mut self, before: usize) {
+        while let Some(comment) = self.comments.get(self.next_comment).filter(|comment| comment.start < before) {
+            let (start, end, text) = (comment.start, comment.end, comment.text.clone());
+            self.next_comment += 1;
+            self.blank_line_before(start);
+            self.output.push_str(&INDENT.repeat(self.depth));
+            self.output.push_str(&text);
+            self.output.push('\n');
+            self.last_end = end;
+            self.at_block_start = false;
+        }
+    }
+    /// Writes `text` as a line standing for `source[start..end]`, with the comments before it.
+    /// A comment after it stays on the same line unless other code comes first, keywords ending a header aside.
+    fn line(&mut self, text: &str, start: usize, end: usize) {
+        self.flush_comments(start);
+        self.blank_line_before(start);
+        self.output.push_str(&INDENT.repeat(self.depth));
+        self.output.push_str(text);
+        self.last_end = end.max(self.last_end);
+        if let Some(comment) = self.comments.get(self.next_comment) {
+            let between = self.source.get(end..comment.start).unwrap_or("\n");
+            if comment.start >= end && !between.contains(['\n', '\r']) && matches!(between.trim(), "" | "THEN" | "DO") {
+                self.output.push(' ');
+                self.output.push_str(&comment.text);
+                self.last_end = comment.end;
+                self.next_comment += 1;
+            }
+        }
+        self.output.push('\n');
+        self.at_block_start = false;
+    }
+    /// A line after which an indented block starts
+    fn opening_line(&mut self, text: &str, start: usize, end: usize) {
+        self.line(text, start, end);
+        self.at_block_start = true;
+        self.depth += 1;
+    }
+    /// Ends the indented block with the comments still inside it, then writes the closing line
+    fn closing_line(&mut self, text: &str, start: usize, end: usize) {
+        self.flush_comments(start);
+        self.depth -= 1;
+        self.at_block_start = true;
+        self.line(text, start, end);
+    }
+    fn commands(&mut self, commands: &Commands) {
+        for command in commands {
+            self.command(command);
+        }
+    }
+    fn command(&mut self, command: &Command) {
+        let span = command.span();
+        match command {
+            Command::Assign(identifier, expression, _) => {
+                self.flush_comments(span.end);
+                self.line(&format!("{} := {};", format_identifier(identifier), format_expression(expression)), span.start, span.end);
+            },
+            Command::If(condition, commands, else_commands, _) => {
+                let condition_end = condition.span().end;
+                self.opening_line(&format!("IF {} THEN", format_condition(condition)), span.start, condition_end);
+                self.commands(commands);
+                let endif = span.end - "ENDIF".len();
+                if let Some(else_commands) = else_commands {
+                    let commands_end = commands.last().map(|command| command.span().end).unwrap_or(condition_end);
+                    let first_else = else_commands.first().map(|command| command.span().start).unwrap_or(endif);
+                    let else_keyword = find_keyword(self.source, commands_end, first_else, "ELSE");
+                    self.closing_line("ELSE", else_keyword, else_keyword + "ELSE".len());
+                    self.depth += 1;
+                    self.commands(else_commands);
+                }
+                self.closing_line("ENDIF", endif, span.end);
+            },
+            Command::While(condition, commands, _) => {
+                self.opening_line(&format!("WHILE {} DO", format_condition(condition)), span.start, condition.span().end);
+                self.commands(commands);
+                self.closing_line("ENDWHILE", span.end - "ENDWHILE".len(), span.end);
+            },
+            Command::Repeat(commands, condition, _) => {
+                self.opening_line("REPEAT", span.start, span.start + "REPEAT".len());
+                self.commands(commands);
+                let commands_end = commands.last().map(|command| command.span().end).unwrap_or(span.start);
+                let until = find_keyword(self.source, commands_end, condition.span().start, "UNTIL");
+                self.closing_line(&format!("UNTIL {};", format_condition(condition)), until, span.end);
+            },
+            Command::ProcCall((procedure_id, arguments), _) => {
+                self.flush_comments(span.end);
+                let arguments: Vec<&str> = arguments.iter().map(|argument| argument.0.as_str()).collect();
+                self.line(&format!("{}({});", procedure_id.0, arguments.join(", ")), span.start, span.end);
+            },
+            Command::Read(identifier, _) => {
+                self.flush_comments(span.end);
+                self.line(&format!("READ {};", format_identifier(identifier)), span.start, span.end);
+            },
+            Command::Write(value, _) => {
+                self.flush_comments(span.end);
+                self.line(&format!("WRITE {};", format_value(value)), span.start, span.end);
+            },
+        }
+    }
+    /// Everything from `IS` to `END`, that is the same for procedures and main
+    fn body(&mut self, header: &str, start: usize, declarations: &Option<Declarations>, commands: &Commands, span: Span) {
+        let first_command = commands.first().map(|command| command.span().start).unwrap_or(span.end);
+        let is_keyword = find_keyword(self.source, start, first_command, "IS");
+        self.opening_line(&format!("{header} IS"), start, is_keyword + "IS".len());
+        let mut declarations_end = is_keyword + "IS".len();
+        if let Some(declarations) = declarations.as_ref().filter(|declarations| !declarations.is_empty()) {
+            let declarations_start = declarations.first().map(|declaration| declaration.span().start).unwrap_or(declarations_end);
+            declarations_end = declarations.last().map(|declaration| declaration.span().end).unwrap_or(declarations_end);
+            self.flush_comments(declarations_end);
+            let formatted: Vec<String> = declarations.iter().map(format_declaration).collect();
+            self.line(&formatted.join(", "), declarations_start, declarations_end);
+        }
+        let in_keyword = find_keyword(self.source, declarations_end, first_command, "IN");
+        self.closing_line("IN", in_keyword, in_keyword + "IN".len());
+        self.depth += 1;
+        self.at_block_start = true;
+        self.commands(commands);
+        self.closing_line("END", span.end - "END".len(), span.end);
+    }
+    /// Top level items are separated by one blank line, comments before them stay with them
+    fn top_level_item(&mut self, start: usize) {
+        if !self.output.is_empty() {
+            self.output.push('\n');
+        }
+        self.at_block_start = true;
+        self.flush_comments(start);
+    }
+    fn program(mut self, program: &Program) -> String {
+        for procedure in program.0.iter().flatten() {
+            let ((name, parameters, _), declarations, commands, span) = procedure;
+            self.top_level_item(span.start);
+            let parameters: Vec<String> = parameters
+                .iter()
+                .map(|parameter| match parameter {
+                    ArgumentsDeclarationVariant::Base(id) => id.0.clone(),
+                    ArgumentsDeclarationVariant::Table(id, _) => format!("T {}", id.0),
+                })
+                .collect();
+            self.body(&format!("PROCEDURE {}({})", name.0, parameters.join(", ")), span.start, declarations, commands, *span);
+        }
+        let (declarations, commands, span) = &program.1;
+        self.top_level_item(span.start);
+        self.body("PROGRAM", span.start, declarations, commands, *span);
+        self.at_block_start = false;
+        self.flush_comments(usize::MAX);
+        self.output
+    }
+}
+
+fn format_identifier(identifier: &Identifier) -> String {
+    match identifier {
+        Identifier::Base(id) => id.0.clone(),
+        Identifier::NumIndexed(id, index, _) => format!("{}[{index}]", id.0),
+        Identifier::PidIndexed(id, index, _) => format!("{}[{}]", id.0, index.0),
+    }
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::Num(num, _) => num.to_string(),
+        Value::Id(identifier) => format_identifier(identifier),
+    }
+}
+
+fn format_expression(expression: &Expression) -> String {
+    let (operator, value_0, value_1) = match expression {
+        Expression::Val(value, _) => return format_value(value),
+        Expression::Add(value_0, value_1, _) => ("+", value_0, value_1),
+        Expression::Substract(value_0, value_1, _) => ("-", value_0, value_1),
+        Expression::Multiply(value_0, value_1, _) => ("*", value_0, value_1),
+        Expression::Divide(value_0, value_1, _) => ("/", value_0, value_1),
+        Expression::Modulo(value_0, value_1, _) => ("%", value_0, value_1),
+    };
+    format!("{} {operator} {}", format_value(value_0), format_value(value_1))
+}
+
+fn format_condition(condition: &Condition) -> String {
+    let (operator, value_0, value_1) = match condition {
+        Condition::Equal(value_0, value_1, _) => ("=", value_0, value_1),
+        Condition::NotEqual(value_0, value_1, _) => ("!=", value_0, value_1),
+        Condition::Greater(value_0, value_1, _) => (">", value_0, value_1),
+        Condition::Lower(value_0, value_1, _) => ("<", value_0, value_1),
+        Condition::GreaterOrEqual(value_0, value_1, _) => (">=", value_0, value_1),
+        Condition::LowerOrEqual(value_0, value_1, _) => ("<=", value_0, value_1),
+    };
+    format!("{} {operator} {}", format_value(value_0), format_value(value_1))
+}
+
+fn format_declaration(declaration: &DeclarationVariant) -> String {
+    match declaration {
+        DeclarationVariant::Base(id) => id.0.clone(),
+        DeclarationVariant::NumIndexed(id, size, _) => format!("{}[{size}]", id.0),
+    }
+}
+
+/// Prints the program in canonical style, keeping its comments.
+/// The result is parsed again and has to give the same AST, up to spans.
+pub fn format(source: &str) -> Result<String, Diagnostic> {
+    let ast = parse(source)?;
+    let formatted = Formatter::new(source).program(&ast);
+    match parse(&formatted) {
+        Ok(formatted_ast) if ast_shape(&formatted_ast) == ast_shape(&ast) => Ok(formatted),
+        _ => Err(Diagnostic::from_compiler_error(&CompilerError::FormattingChangedProgram)),
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::assembler::{body_cost, call_cost, call_length, linkage_length, subroutine_length};
+use crate::ir::*;
+
+/// How the procedures copied into their calls are chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Strategy {
+    /// Unless the copies would make the program bigger than one subroutine and the calls to it
+    Shorter,
+    /// Unless a call would cost more to run, with the variables the `registers` pass may keep in registers when it runs
+    Faster { registers: bool },
+    /// Every procedure, to compare the other strategies with
+    #[cfg(test)]
+    All,
+}
+
+/// Copies the procedures `strategy` chooses into their calls. Returns whether any procedure is inlined.
+pub(crate) fn inline(module: &mut Module, strategy: Strategy) -> bool {
+    let inlined = match strategy {
+        Strategy::Shorter => choose_shorter(module),
+        Strategy::Faster { registers } => choose_faster(module, registers),
+        #[cfg(test)]
+        Strategy::All => module.functions.iter().filter_map(|function| function.name.clone()).collect(),
+    };
+    // Callees come before their callers, so they are copied with their own calls inlined already
+    for index in 0..module.functions.len() {
+        let (callees, rest) = module.functions.split_at_mut(index);
+        inline_calls(&mut rest[0], callees, &inlined);
+    }
+    !inlined.is_empty()
+}
+
+/// Callers come after their callees, so going backwards from main every procedure knows how often it is emitted
+fn choose_shorter(module: &Module) -> Vec<String> {
+    let mut sites: HashMap<&str, u64> = HashMap::new();
+    let mut inlined: Vec<String> = Vec::new();
+    for function in module.functions.iter().rev() {
+        let Some(name) = &function.name else {
+            count_calls(function, 1, &mut sites);
+            continue;
+        };
+        let count = sites.get(name.as_str()).copied().unwrap_or(0);
+        let body_length = subroutine_length(module, function);
+        let copies_length = count.saturating_mul(body_length);
+        let compiled_length = body_length + linkage_length(function.return_slot) + count.saturating_mul(call_length(function));
+        let inline = count <= 1 || copies_length <= compiled_length;
+        if inline {
+            inlined.push(name.clone());
+        }
+        count_calls(function, if inline { count } else { count.min(1) }, &mut sites);
+    }
+    inlined
+}
+
+/// Going backwards from main every caller is chosen for already. The calls a procedure makes cost the same either way.
+/// Copied, its variables stay in memory, as the cells of its locals are used by other procedures too,
+/// while compiled as a subroutine its locals may go to registers but its parameters are references.
+fn choose_faster(module: &Module, registers: bool) -> Vec<String> {
+    // The variables of every function, indexed like `module.functions`, that may go to registers where they end up
+    let mut kept: Vec<HashSet<String>> = vec![HashSet::new(); module.functions.len()];
+    let mut inlined: Vec<String> = Vec::new();
+    for (index, function) in module.functions.iter().enumerate().rev() {
+        let locals: HashSet<String> = match registers {
+            true => function.locals.iter().map(|local| local.name.clone()).collect(),
+            false => HashSet::new(),
+        };
+        let Some(name) = &function.name else {
+            kept[index] = locals;
+            continue;
+        };
+        // Copied, the locals share cells with other procedures, and a parameter is whatever its arguments are
+        let mut parameters: HashSet<String> = function.parameters.iter().map(|parameter| parameter.name.clone()).collect();
+        for (caller, caller_kept) in module.functions.iter().zip(&kept).skip(index + 1) {
+            for operation in caller.blocks.iter().flat_map(|block| &block.operations) {
+                if let Operation::Call(callee, arguments) = operation {
+                    if callee == name {
+                        for (parameter, argument) in function.parameters.iter().zip(arguments) {
+                            if !caller_kept.contains(argument) {
+                                parameters.remove(&parameter.name);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let copied_cost = body_cost(module, function, true, &parameters);
+        let compiled_cost = body_cost(module, function, false, &locals).saturating_add(call_cost(function));
+        if copied_cost <= compiled_cost {
+            inlined.push(name.clone());
+            kept[index] = parameters;
+        } else {
+            kept[index] = locals;
+        }
+    }
+    inlined
+}
+
+/// Adds `copies` to the number of times every procedure `function` calls is emitted
+fn count_calls<'a>(function: &'a Function, copies: u64, sites: &mut HashMap<&'a str, u64>) {
+    for operation in function.blocks.iter().flat_map(|block| &block.operations) {
+        if let Operation::Call(callee, _) = operation {
+            let count = sites.entry(callee).or_default();
+            *count = count.saturating_add(copies);
+        }
+    }
+}
+
+/// Replaces the calls of `function` to the `inlined` procedures by their blocks
+fn inline_calls(function: &mut Function, callees: &[Function], inlined: &[String]) {
+    let mut index = 0;
+    while index < function.blocks.len() {
+        let call = function.blocks[index].operations.iter().position(|operation| {
+            matches!(operation, Operation::Call(callee, _) if inlined.contains(callee))
+        });
+        let Some(position) = call else {
+            index += 1;
+            continue;
+        };
+        let Operation::Call(name, arguments) = function.blocks[index].operations[position].clone() else {
+            index += 1;
+            continue;
+        };
+        let Some(callee) = callees.iter().find(|callee| callee.name.as_ref() == Some(&name)) else {
+            index += 1;
+            continue;
+        };
+        let origin = function.blocks[index].origins[position];
+        // The operations after the call go on in a block of their own
+        let block = &mut function.blocks[index];
+        let continuation = Block {
+            operations: block.operations.split_off(position + 1),
+            origins: block.origins.split_off(position + 1),
+            terminator: std::mem::replace(&mut block.terminator, Terminator::Jump(BlockId(0))),
+            terminator_origin: block.terminator_origin.replace(origin),
+        };
+        block.operations.pop();
+        block.origins.pop();
+        let entry = BlockId(function.blocks.len());
+        let after = BlockId(entry.0 + callee.blocks.len());
+        function.blocks[index].terminator = Terminator::Jump(entry);
+        let mut names: HashMap<&str, String> = HashMap::new();
+        for (parameter, argument) in callee.parameters.iter().zip(&arguments) {
+            names.insert(&parameter.name, argument.clone());
+        }
+        for local in &callee.locals {
+            names.insert(&local.name, qualified(&local.name, &name));
+        }
+        for mut block in callee.blocks.iter().cloned() {
+            for operation in &mut block.operations {
+                if let Some(temp) = operation.defined_mut() {
+                    temp.0 += function.temps;
+                }
+                for operand in operation.operands_mut() {
+                    offset(operand, function.temps);
+                }
+                for variable in operation.variables_mut() {
+                    if let Some(name) = names.get(variable.as_str()) {
+                        *variable = name.clone();
+                    }
+                }
+            }
+            for operand in block.terminator.operands_mut() {
+                offset(operand, function.temps);
+            }
+            match &mut block.terminator {
+                Terminator::Return => block.terminator = Terminator::Jump(after),
+                terminator => {
+                    for successor in terminator.successors_mut() {
+                        successor.0 += entry.0;
+                    }
+                },
+            }
+            block.origins.fill(origin);
+            block.terminator_origin = Some(origin);
+            function.blocks.push(block);
+        }
+        function.blocks.push(continuation);
+        function.temps += callee.temps;
+        // There is no recursion, so the one copy of the locals is never in use twice at a time
+        for local in &callee.locals {
+            let name = qualified(&local.name, &name);
+            if function.local(&name).is_none() {
+                function.locals.push(Local { name, ..local.clone() });
+            }
+        }
+        function.uninitialised.extend(callee.uninitialised.iter().map(|variable| qualified(variable, &name)));
+    }
+    function.renumber();
+}
+
+/// The name of a variable of `procedure` copied into another body, variables it got from its own callees keep theirs
+fn qualified(variable: &str, procedure: &str) -> String {
+    match variable.contains('@') {
+        true => variable.to_string(),
+        false => format!("{variable}@{procedure}"),
+    }
+}
+
+fn offset(operand: &mut Operand, temps: usize) {
+    if let Operand::Temp(temp) = operand {
+        temp.0 += temps;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{ir, parse, vm, CompileOptions};
+
+    #[test]
+    fn inlining_costs_no_more_than_inlining_everything() {
+        let options = CompileOptions::default();
+        let pipeline = options.passes.pipeline(options.opt_level);
+        for entry in fs::read_dir(format!("{}/examples/gembala", env!("CARGO_MANIFEST_DIR"))).unwrap() {
+            let path = entry.unwrap().path();
+            let source = fs::read_to_string(&path).unwrap();
+            // Examples without a documented input get sevens
+            let documented = source.lines().filter_map(|line| line.strip_prefix("# ?")).map(str::trim);
+            let input: Vec<&str> = documented.chain(["7"; 8]).collect();
+            let mut runs = Vec::new();
+            for inline_all in [false, true] {
+                let mut module = ir::lower(&parse(&source).unwrap()).0.unwrap();
+                // Nothing is left for the `inline` pass of the pipeline to copy
+                if inline_all {
+                    inline(&mut module, Strategy::All);
+                }
+                let (_, assembler, _) = pipeline.run(module, &options, &[]);
+                let mut output: Vec<u8> = Vec::new();
+                let execution = vm::run(&assembler.assemble(), &mut input.join("\n").as_bytes(), &mut output).unwrap();
+                runs.push((output, execution.cost));
+            }
+            assert_eq!(runs[0].0, runs[1].0, "{}", path.display());
+            assert!(runs[0].1 <= runs[1].1, "{} costs {} instead of {}", path.display(), runs[0].1, runs[1].1);
+        }
+    }
+}
@@ -0,0 +1,1045 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+
+use num_traits::{ToPrimitive, Zero};
+
+use crate::analysis::check_initialisation;
+use crate::assembler::{CompilerError, CompilerWarning, Register};
+use crate::ast::*;
+use crate::callgraph::CallGraph;
+use crate::diagnostics::DiagnosticSink;
+
+/// A value computed once, `%n` in the dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Temp(pub usize);
+
+impl Display for Temp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operand {
+    Temp(Temp),
+    Const(Num),
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operand::Temp(temp) => write!(f, "{temp}"),
+            Operand::Const(num) => write!(f, "{num}"),
+        }
+    }
+}
+
+/// A memory slot: a scalar, or an element of an array
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Place {
+    Variable(String),
+    Element(String, Operand),
+}
+
+impl Place {
+    pub fn name(&self) -> &str {
+        match self {
+            Place::Variable(name) | Place::Element(name, _) => name,
+        }
+    }
+    pub fn name_mut(&mut self) -> &mut String {
+        match self {
+            Place::Variable(name) | Place::Element(name, _) => name,
+        }
+    }
+    pub fn index_mut(&mut self) -> Option<&mut Operand> {
+        match self {
+            Place::Variable(_) => None,
+            Place::Element(_, index) => Some(index),
+        }
+    }
+}
+
+impl Display for Place {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Place::Variable(name) => write!(f, "{name}"),
+            Place::Element(name, index) => write!(f, "{name}[{index}]"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    /// Shifts by a constant number of bits
+    Shl,
+    Shr,
+}
+
+impl BinaryOp {
+    /// The result the virtual machine gets: subtraction stops at zero and dividing by zero gives zero
+    pub fn fold(self, num_0: &Num, num_1: &Num) -> Num {
+        match self {
+            BinaryOp::Add => num_0 + num_1,
+            BinaryOp::Sub if num_0 < num_1 => Num::zero(),
+            BinaryOp::Sub => num_0 - num_1,
+            BinaryOp::Mul => num_0 * num_1,
+            BinaryOp::Div | BinaryOp::Mod if num_1.is_zero() => Num::zero(),
+            BinaryOp::Div => num_0 / num_1,
+            BinaryOp::Mod => num_0 % num_1,
+            BinaryOp::Shl => num_0 << num_1.to_usize().unwrap_or(0),
+            BinaryOp::Shr => num_0 >> num_1.to_usize().unwrap_or(0),
+        }
+    }
+}
+
+impl Display for BinaryOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BinaryOp::Add => write!(f, "+"),
+            BinaryOp::Sub => write!(f, "-"),
+            BinaryOp::Mul => write!(f, "*"),
+            BinaryOp::Div => write!(f, "/"),
+            BinaryOp::Mod => write!(f, "%"),
+            BinaryOp::Shl => write!(f, "<<"),
+            BinaryOp::Shr => write!(f, ">>"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Relation {
+    Equal,
+    NotEqual,
+    Greater,
+    Lower,
+    GreaterOrEqual,
+    LowerOrEqual,
+}
+
+impl Relation {
+    pub fn holds(self, num_0: &Num, num_1: &Num) -> bool {
+        match self {
+            Relation::Equal => num_0 == num_1,
+            Relation::NotEqual => num_0 != num_1,
+            Relation::Greater => num_0 > num_1,
+            Relation::Lower => num_0 < num_1,
+            Relation::GreaterOrEqual => num_0 >= num_1,
+            Relation::LowerOrEqual => num_0 <= num_1,
+        }
+    }
+}
+
+impl Display for Relation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Relation::Equal => write!(f, "="),
+            Relation::NotEqual => write!(f, "!="),
+            Relation::Greater => write!(f, ">"),
+            Relation::Lower => write!(f, "<"),
+            Relation::GreaterOrEqual => write!(f, ">="),
+            Relation::LowerOrEqual => write!(f, "<="),
+        }
+    }
+}
+
+/// A three-address instruction inside a block.
+/// A temporary is used once, later in the block that assigns it, and no store, read or call comes in between.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    Binary(Temp, BinaryOp, Operand, Operand),
+    Load(Temp, Place),
+    Store(Place, Operand),
+    Read(Place),
+    Write(Operand),
+    /// The callee and the variables passed to it
+    Call(String, Vec<String>),
+}
+
+impl Operation {
+    /// The temporary the operation assigns
+    pub fn defined(&self) -> Option<Temp> {
+        match self {
+            Operation::Binary(temp, _, _, _) | Operation::Load(temp, _) => Some(*temp),
+            Operation::Store(_, _) | Operation::Read(_) | Operation::Write(_) | Operation::Call(_, _) => None,
+        }
+    }
+    pub fn defined_mut(&mut self) -> Option<&mut Temp> {
+        match self {
+            Operation::Binary(temp, _, _, _) | Operation::Load(temp, _) => Some(temp),
+            Operation::Store(_, _) | Operation::Read(_) | Operation::Write(_) | Operation::Call(_, _) => None,
+        }
+    }
+    /// The operands the operation reads, indices of array elements included
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Operation::Binary(_, _, operand_0, operand_1) => vec![operand_0, operand_1],
+            Operation::Load(_, place) | Operation::Read(place) => place.index_mut().into_iter().collect(),
+            Operation::Store(place, operand) => place.index_mut().into_iter().chain([operand]).collect(),
+            Operation::Write(operand) => vec![operand],
+            Operation::Call(_, _) => Vec::new(),
+        }
+    }
+    /// The names of the variables the operation uses, arguments of calls included
+    pub fn variables_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            Operation::Load(_, place) | Operation::Store(place, _) | Operation::Read(place) => vec![place.name_mut()],
+            Operation::Call(_, arguments) => arguments.iter_mut().collect(),
+            Operation::Binary(_, _, _, _) | Operation::Write(_) => Vec::new(),
+        }
+    }
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operation::Binary(temp, op, operand_0, operand_1) => write!(f, "{temp} = {operand_0} {op} {operand_1}"),
+            Operation::Load(temp, place) => write!(f, "{temp} = load {place}"),
+            Operation::Store(place, operand) => write!(f, "store {place}, {operand}"),
+            Operation::Read(place) => write!(f, "read {place}"),
+            Operation::Write(operand) => write!(f, "write {operand}"),
+            Operation::Call(name, arguments) => write!(f, "call {name}({})", arguments.join(", ")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId(pub usize);
+
+impl Display for BlockId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "bb{}", self.0)
+    }
+}
+
+/// How a block ends, the edges of the control-flow graph
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Terminator {
+    Jump(BlockId),
+    /// Goes to the first block when the relation holds and to the second one otherwise
+    Branch(Relation, Operand, Operand, BlockId, BlockId),
+    Return,
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(block) => vec![*block],
+            Terminator::Branch(_, _, _, yes, no) => vec![*yes, *no],
+            Terminator::Return => Vec::new(),
+        }
+    }
+    pub fn successors_mut(&mut self) -> Vec<&mut BlockId> {
+        match self {
+            Terminator::Jump(block) => vec![block],
+            Terminator::Branch(_, _, _, yes, no) => vec![yes, no],
+            Terminator::Return => Vec::new(),
+        }
+    }
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Terminator::Branch(_, operand_0, operand_1, _, _) => vec![operand_0, operand_1],
+            Terminator::Jump(_) | Terminator::Return => Vec::new(),
+        }
+    }
+}
+
+impl Display for Terminator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Terminator::Jump(block) => write!(f, "jump {block}"),
+            Terminator::Branch(relation, operand_0, operand_1, yes, no) => write!(f, "branch {operand_0} {relation} {operand_1}, {yes}, {no}"),
+            Terminator::Return => write!(f, "return"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub operations: Vec<Operation>,
+    /// The command each operation comes from, an index into `Function::commands`
+    pub origins: Vec<usize>,
+    pub terminator: Terminator,
+    /// `None` for the return ending the body
+    pub terminator_origin: Option<usize>,
+}
+
+/// A command of the source, for the cost report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceCommand {
+    pub span: Span,
+    /// Commands it is nested in
+    pub depth: usize,
+    /// Loops it is nested in
+    pub loops: usize,
+    /// The command it is nested in, which comes before it
+    pub parent: Option<usize>,
+}
+
+/// A parameter of a procedure, its slot holds the address of the argument
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Parameter {
+    pub name: String,
+    pub array: bool,
+    pub slot: u64,
+}
+
+/// A variable of a function with its cells, an array when it has a size
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Local {
+    pub name: String,
+    pub size: Option<u64>,
+    pub address: u64,
+}
+
+/// A procedure or main, its entry is the first block
+#[derive(Debug, Clone)]
+pub struct Function {
+    /// `None` for main
+    pub name: Option<String>,
+    pub parameters: Vec<Parameter>,
+    /// Declared variables, and those of the procedures copied into it as `name@procedure`
+    pub locals: Vec<Local>,
+    /// Holds the address to return to, unused for main
+    pub return_slot: u64,
+    /// Number of temporaries, `%0` up to `%{temps - 1}`
+    pub temps: usize,
+    /// Ordered so that every block comes before the blocks it reaches, loops aside
+    pub blocks: Vec<Block>,
+    /// The commands of the body in source order, the ones of copied procedures are not among them
+    pub commands: Vec<SourceCommand>,
+    /// Locals that may be read before being assigned
+    pub uninitialised: HashSet<String>,
+    /// Scalars kept in registers, chosen by the `registers` pass
+    pub registers: Vec<(Register, String)>,
+}
+
+impl Function {
+    pub fn successors(&self, block: BlockId) -> Vec<BlockId> {
+        self.blocks[block.0].terminator.successors()
+    }
+    /// The blocks leading to every block, indexed like `blocks`
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut predecessors = vec![Vec::new(); self.blocks.len()];
+        for (index, block) in self.blocks.iter().enumerate() {
+            for successor in block.terminator.successors() {
+                if !predecessors[successor.0].contains(&BlockId(index)) {
+                    predecessors[successor.0].push(BlockId(index));
+                }
+            }
+        }
+        predecessors
+    }
+    pub fn local(&self, name: &str) -> Option<&Local> {
+        self.locals.iter().find(|local| local.name == name)
+    }
+    /// Renumbers the blocks in reverse postorder from the entry, dropping the ones never reached.
+    /// Branches are followed to their second block first, so the first one comes right after them.
+    pub fn renumber(&mut self) {
+        let mut postorder: Vec<usize> = Vec::new();
+        let mut visited = vec![false; self.blocks.len()];
+        // Blocks with the successors left to visit
+        let mut stack: Vec<(usize, Vec<BlockId>)> = vec![(0, self.blocks[0].terminator.successors())];
+        visited[0] = true;
+        while let Some((block, successors)) = stack.last_mut() {
+            match successors.pop() {
+                Some(successor) if !visited[successor.0] => {
+                    visited[successor.0] = true;
+                    stack.push((successor.0, self.blocks[successor.0].terminator.successors()));
+                },
+                Some(_) => {},
+                None => {
+                    postorder.push(*block);
+                    stack.pop();
+                },
+            }
+        }
+        let mut numbers: HashMap<usize, BlockId> = HashMap::new();
+        for (number, block) in postorder.iter().rev().enumerate() {
+            numbers.insert(*block, BlockId(number));
+        }
+        let mut blocks = std::mem::take(&mut self.blocks);
+        self.blocks = postorder
+            .iter()
+            .rev()
+            .map(|block| {
+                let mut block = std::mem::replace(&mut blocks[*block], Block::empty());
+                for successor in block.terminator.successors_mut() {
+                    *successor = numbers[&successor.0];
+                }
+                block
+            })
+            .collect();
+    }
+    /// How many loops each block is in. A loop is a block some later block jumps back to, with the blocks on the way.
+    pub fn loop_depths(&self) -> Vec<usize> {
+        let predecessors = self.predecessors();
+        let mut depths = vec![0; self.blocks.len()];
+        for (tail, block) in self.blocks.iter().enumerate() {
+            for header in block.terminator.successors().into_iter().filter(|header| header.0 <= tail) {
+                // Going backwards from the jump back, the header ends the walk
+                let mut in_loop: HashSet<usize> = HashSet::from([header.0]);
+                let mut stack = vec![tail];
+                while let Some(block) = stack.pop() {
+                    if in_loop.insert(block) {
+                        stack.extend(predecessors[block].iter().map(|predecessor| predecessor.0));
+                    }
+                }
+                for block in in_loop {
+                    depths[block] += 1;
+                }
+            }
+        }
+        depths
+    }
+}
+
+impl Block {
+    fn empty() -> Block {
+        Block { operations: Vec::new(), origins: Vec::new(), terminator: Terminator::Return, terminator_origin: None }
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => {
+                let parameters: Vec<String> =
+                    self.parameters.iter().map(|parameter| if parameter.array { format!("T {}", parameter.name) } else { parameter.name.clone() }).collect();
+                writeln!(f, "procedure {name}({}):", parameters.join(", "))?;
+            },
+            None => writeln!(f, "main:")?,
+        }
+        if !self.locals.is_empty() {
+            let locals: Vec<String> = self
+                .locals
+                .iter()
+                .map(|local| match local.size {
+                    Some(size) => format!("{}[{size}]", local.name),
+                    None => local.name.clone(),
+                })
+                .collect();
+            writeln!(f, "  locals {}", locals.join(", "))?;
+        }
+        if !self.registers.is_empty() {
+            let registers: Vec<String> = self.registers.iter().map(|(register, name)| format!("{name} in {register}")).collect();
+            writeln!(f, "  registers {}", registers.join(", "))?;
+        }
+        let predecessors = self.predecessors();
+        for (index, block) in self.blocks.iter().enumerate() {
+            let from: Vec<String> = predecessors[index].iter().map(BlockId::to_string).collect();
+            match from.is_empty() {
+                true => writeln!(f, "{}:", BlockId(index))?,
+                false => writeln!(f, "{}:  # from {}", BlockId(index), from.join(", "))?,
+            }
+            for operation in &block.operations {
+                writeln!(f, "  {operation}")?;
+            }
+            writeln!(f, "  {}", block.terminator)?;
+        }
+        Ok(())
+    }
+}
+
+/// The procedures in declaration order, then main
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub functions: Vec<Function>,
+}
+
+impl Module {
+    pub fn procedure(&self, name: &str) -> Option<&Function> {
+        self.functions.iter().find(|function| function.name.as_deref() == Some(name))
+    }
+}
+
+/// The text of `--emit=ir`, functions separated by an empty line
+impl Display for Module {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, function) in self.functions.iter().enumerate() {
+            if index != 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{function}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the program and lowers it, giving every variable its cells. Only the first of procedures declared twice is kept.
+/// Returns every error found in main and in all procedures sorted by position, and the warnings either way.
+pub fn lower(program: &Program) -> (Result<Module, Vec<CompilerError>>, Vec<CompilerWarning>) {
+    let mut checker = Checker::new(program);
+    let initialisation_warnings = check_initialisation(program);
+    checker.note_uninitialised(&initialisation_warnings);
+    let (main_locals, main_scope) = checker.declare_main(&program.1 .0);
+    let frames = checker.allocate_frames();
+    let mut functions: Vec<Function> = Vec::new();
+    for (procedure, frame) in checker.procedures.clone().into_iter().zip(frames) {
+        let ((name, _, _), _, commands, _) = procedure;
+        let mut scope: HashMap<String, Variable> = HashMap::new();
+        for (index, parameter) in frame.parameters.iter().enumerate() {
+            scope.insert(parameter.name.clone(), if parameter.array { Variable::ArrayParameter(index) } else { Variable::ScalarParameter });
+        }
+        for local in &frame.locals {
+            scope.insert(local.name.clone(), local.size.map_or(Variable::Scalar, Variable::Array));
+        }
+        let body = FunctionBuilder::new(&mut checker, Some(&name.0), scope).build(commands);
+        functions.push(Function { name: Some(name.0.clone()), parameters: frame.parameters, locals: frame.locals, return_slot: frame.return_slot, ..body });
+    }
+    let body = FunctionBuilder::new(&mut checker, None, main_scope).build(&program.1 .1);
+    functions.push(Function { locals: main_locals, ..body });
+    checker.check_unused();
+    for warning in initialisation_warnings {
+        checker.diagnostics.push_warning(warning);
+    }
+    let warnings = checker.diagnostics.take_warnings();
+    if checker.diagnostics.has_errors() {
+        return (Err(checker.diagnostics.into_sorted()), warnings);
+    }
+    (Ok(Module { functions }), warnings)
+}
+
+/// What a name stands for in a body
+#[derive(Debug, Clone, Copy)]
+enum Variable {
+    Scalar,
+    /// With its size
+    Array(u64),
+    ScalarParameter,
+    /// With the position of the parameter
+    ArrayParameter(usize),
+}
+
+/// The parameters, locals and return slot of a procedure
+struct Frame {
+    parameters: Vec<Parameter>,
+    locals: Vec<Local>,
+    return_slot: u64,
+}
+
+/// What is known about the whole program while its functions are lowered one by one
+struct Checker<'a> {
+    program: &'a Program,
+    diagnostics: DiagnosticSink,
+    /// The first declaration of every procedure, in declaration order
+    procedures: Vec<&'a Procedure>,
+    call_graph: CallGraph,
+    /// Calls to procedures not declared before the caller, by the span of the call. They are never lowered.
+    rejected_calls: HashSet<Span>,
+    /// Cells an array parameter needs for its constant indices, by procedure and position: the size with the name and span of the largest index
+    table_requirements: HashMap<(String, usize), (u64, String, Span)>,
+    called_procedures: HashSet<String>,
+    /// Named `name@procedure` inside procedures
+    referenced_variables: HashSet<String>,
+    /// Variables possibly read before being assigned, named like `referenced_variables`
+    uninitialised: HashSet<String>,
+    memory_pointer: u64,
+}
+
+impl<'a> Checker<'a> {
+    fn new(program: &'a Program) -> Checker<'a> {
+        let mut diagnostics = DiagnosticSink::new();
+        let mut procedures: Vec<&Procedure> = Vec::new();
+        let mut call_graph = CallGraph::new();
+        for procedure in program.0.iter().flatten() {
+            let name = &procedure.0 .0;
+            if let Some(first) = procedures.iter().find(|first| first.0 .0 .0 == name.0) {
+                diagnostics.push(CompilerError::DuplicateProcedureDeclaration(name.0.clone(), name.1, first.0 .0 .1));
+                continue;
+            }
+            call_graph.add_procedure(&name.0, name.1, &procedure.2);
+            procedures.push(procedure);
+        }
+        let mut rejected_calls: HashSet<Span> = HashSet::new();
+        for error in call_graph.check() {
+            rejected_calls.extend(error.get_span());
+            diagnostics.push(error);
+        }
+        Checker {
+            program,
+            diagnostics,
+            procedures,
+            call_graph,
+            rejected_calls,
+            table_requirements: HashMap::new(),
+            called_procedures: HashSet::new(),
+            referenced_variables: HashSet::new(),
+            uninitialised: HashSet::new(),
+            memory_pointer: 0,
+        }
+    }
+    /// Gives the variables of main their cells, from the start of the memory
+    fn declare_main(&mut self, declarations: &Option<Declarations>) -> (Vec<Local>, HashMap<String, Variable>) {
+        let mut locals: Vec<Local> = Vec::new();
+        let mut scope: HashMap<String, Variable> = HashMap::new();
+        let mut declared_names: HashMap<String, Span> = HashMap::new();
+        for declaration in declarations.iter().flatten() {
+            let id = match declaration {
+                DeclarationVariant::Base(id) | DeclarationVariant::NumIndexed(id, _, _) => id,
+            };
+            if let Some(first) = declared_names.get(&id.0) {
+                self.diagnostics.push(CompilerError::DuplicateVariableDeclaration(id.0.clone(), id.1, *first));
+                continue;
+            }
+            declared_names.insert(id.0.clone(), id.1);
+            let (local, variable) = self.declare(declaration);
+            locals.push(local);
+            scope.insert(id.0.clone(), variable);
+        }
+        (locals, scope)
+    }
+    /// Reserves the cells of a declared variable at `memory_pointer`
+    fn declare(&mut self, declaration: &DeclarationVariant) -> (Local, Variable) {
+        match declaration {
+            DeclarationVariant::Base(id) => {
+                let address = self.reserve(1).unwrap_or_else(|| {
+                    self.diagnostics.push(CompilerError::OutOfMemory(id.0.clone(), id.1));
+                    0
+                });
+                (Local { name: id.0.clone(), size: None, address }, Variable::Scalar)
+            },
+            DeclarationVariant::NumIndexed(id, size, span) => match size.to_u64().and_then(|size| Some((size, self.reserve(size)?))) {
+                Some((size, address)) => (Local { name: id.0.clone(), size: Some(size), address }, Variable::Array(size)),
+                None => {
+                    self.diagnostics.push(CompilerError::ArrayTooLarge(id.0.clone(), *span));
+                    // Placeholder so that uses of the array are not reported as undeclared
+                    (Local { name: id.0.clone(), size: Some(u64::MAX), address: 0 }, Variable::Array(u64::MAX))
+                },
+            },
+        }
+    }
+    /// Reserves `size` cells at `memory_pointer`, `None` if they run past the end of the memory
+    fn reserve(&mut self, size: u64) -> Option<u64> {
+        let start = self.memory_pointer;
+        self.memory_pointer = start.checked_add(size)?;
+        Some(start)
+    }
+    /// Reserves a cell holding an address, `name` is blamed if there is no room left
+    fn reserve_slot(&mut self, name: &str, span: Span) -> u64 {
+        self.reserve(1).unwrap_or_else(|| {
+            self.diagnostics.push(CompilerError::OutOfMemory(name.to_string(), span));
+            0
+        })
+    }
+    /// Gives every procedure its locals and slots, placed after the ones of every procedure calling it.
+    /// Procedures never active at the same time share memory, so memory use grows with the depth of calls and not with their number.
+    fn allocate_frames(&mut self) -> Vec<Frame> {
+        let base = self.memory_pointer;
+        let mut end = base;
+        let mut floors: HashMap<String, u64> = HashMap::new();
+        let mut frames: Vec<Frame> = Vec::new();
+        // Callers are declared after their callees, so they get their memory first
+        for procedure in self.procedures.clone().into_iter().rev() {
+            let ((name, declared_arguments, _), declarations, _, _) = procedure;
+            self.memory_pointer = floors.get(&name.0).copied().unwrap_or(base);
+            let locals = self.declare_procedure_locals(declared_arguments, declarations);
+            let return_slot = self.reserve_slot(&name.0, name.1);
+            let parameters = declared_arguments
+                .iter()
+                .map(|declared_argument| {
+                    let (id, array) = match declared_argument {
+                        ArgumentsDeclarationVariant::Base(id) => (id, false),
+                        ArgumentsDeclarationVariant::Table(id, _) => (id, true),
+                    };
+                    Parameter { name: id.0.clone(), array, slot: self.reserve_slot(&id.0, id.1) }
+                })
+                .collect();
+            for callee in self.call_graph.callees(&name.0) {
+                let floor = floors.entry(callee.to_string()).or_insert(base);
+                *floor = (*floor).max(self.memory_pointer);
+            }
+            end = end.max(self.memory_pointer);
+            frames.push(Frame { parameters, locals, return_slot });
+        }
+        self.memory_pointer = end;
+        frames.reverse();
+        frames
+    }
+    /// Gives the locals of a procedure their cells at `memory_pointer`.
+    /// Records names declared twice among the procedure's parameters and locals.
+    fn declare_procedure_locals(&mut self, declared_arguments: &ArgumentsDeclaration, declarations: &Option<Declarations>) -> Vec<Local> {
+        let mut declared_names: HashMap<String, Span> = HashMap::new();
+        let arguments = declared_arguments.iter().map(|declared_argument| match declared_argument {
+            ArgumentsDeclarationVariant::Base(id) | ArgumentsDeclarationVariant::Table(id, _) => (id, None),
+        });
+        let declarations = declarations.iter().flatten().map(|declaration| match declaration {
+            DeclarationVariant::Base(id) | DeclarationVariant::NumIndexed(id, _, _) => (id, Some(declaration)),
+        });
+        let mut locals: Vec<Local> = Vec::new();
+        for (id, declaration) in arguments.chain(declarations) {
+            if let Some(first) = declared_names.get(&id.0) {
+                self.diagnostics.push(CompilerError::DuplicateVariableDeclaration(id.0.clone(), id.1, *first));
+                continue;
+            }
+            declared_names.insert(id.0.clone(), id.1);
+            if let Some(declaration) = declaration {
+                locals.push(self.declare(declaration).0);
+            }
+        }
+        locals
+    }
+    /// Records that the array behind a parameter of `procedure` needs `required` cells, keeping the largest need
+    fn require_table_size(&mut self, procedure: &str, parameter: usize, required: u64, name: String, span: Span) {
+        let requirement = self.table_requirements.entry((procedure.to_string(), parameter)).or_insert((0, name.clone(), span));
+        if required > requirement.0 {
+            *requirement = (required, name, span);
+        }
+    }
+    /// Records the variables the initialisation check warns about, by their name in `referenced_variables`
+    fn note_uninitialised(&mut self, warnings: &[CompilerWarning]) {
+        for warning in warnings {
+            let (CompilerWarning::UsedBeforeInitialisation(name, span) | CompilerWarning::PossiblyUsedBeforeInitialisation(name, span)) = warning
+            else {
+                continue;
+            };
+            let procedure = self.program.0.iter().flatten().find(|procedure| procedure.3.start <= span.start && span.end <= procedure.3.end);
+            self.uninitialised.insert(match procedure {
+                Some(procedure) => format!("{name}@{}", procedure.0 .0 .0),
+                None => name.clone(),
+            });
+        }
+    }
+    /// Warns about variables never referenced and procedures never called
+    fn check_unused(&mut self) {
+        let mut declared: Vec<Pidentifier> = Vec::new();
+        let declaration_id = |declaration: &DeclarationVariant| match declaration {
+            DeclarationVariant::Base(id) | DeclarationVariant::NumIndexed(id, _, _) => id.clone(),
+        };
+        declared.extend(self.program.1 .0.iter().flatten().map(declaration_id));
+        for procedure in self.program.0.iter().flatten() {
+            let name = &procedure.0 .0 .0;
+            if !self.called_procedures.contains(name) && !self.call_graph.is_called(name) {
+                self.diagnostics.push_warning(CompilerWarning::UnusedProcedure(name.clone(), procedure.0 .0 .1));
+            }
+            let arguments = procedure.0 .1.iter().map(|argument| match argument {
+                ArgumentsDeclarationVariant::Base(id) | ArgumentsDeclarationVariant::Table(id, _) => id.clone(),
+            });
+            let locals = procedure.1.iter().flatten().map(declaration_id);
+            declared.extend(arguments.chain(locals).map(|id| (format!("{}@{}", id.0, name), id.1)));
+        }
+        for id in declared {
+            if !self.referenced_variables.contains(&id.0) {
+                self.diagnostics.push_warning(CompilerWarning::UnusedVariable(id.0, id.1));
+            }
+        }
+    }
+}
+
+/// Checks and lowers the commands of one body, appending to the current block.
+/// Errors are recorded and the lowering carries on, the function is thrown away then.
+struct FunctionBuilder<'c, 'a> {
+    checker: &'c mut Checker<'a>,
+    /// `None` for main
+    name: Option<&'a str>,
+    scope: HashMap<String, Variable>,
+    blocks: Vec<Block>,
+    current: usize,
+    temps: usize,
+    commands: Vec<SourceCommand>,
+    /// The command being lowered
+    origin: Option<usize>,
+    depth: usize,
+    loops: usize,
+}
+
+impl<'c, 'a> FunctionBuilder<'c, 'a> {
+    fn new(checker: &'c mut Checker<'a>, name: Option<&'a str>, scope: HashMap<String, Variable>) -> FunctionBuilder<'c, 'a> {
+        FunctionBuilder { checker, name, scope, blocks: Vec::new(), current: 0, temps: 0, commands: Vec::new(), origin: None, depth: 0, loops: 0 }
+    }
+    /// The function without its parameters, locals and return slot
+    fn build(mut self, commands: &Commands) -> Function {
+        self.current = self.new_block().0;
+        self.lower_commands(commands);
+        self.terminate(Terminator::Return);
+        let uninitialised = match self.name {
+            Some(name) => {
+                let suffix = format!("@{name}");
+                self.checker.uninitialised.iter().filter_map(|variable| variable.strip_suffix(&suffix)).map(str::to_string).collect()
+            },
+            None => self.checker.uninitialised.iter().filter(|variable| !variable.contains('@')).cloned().collect(),
+        };
+        let mut function = Function {
+            name: None,
+            parameters: Vec::new(),
+            locals: Vec::new(),
+            return_slot: 0,
+            temps: self.temps,
+            blocks: self.blocks,
+            commands: self.commands,
+            uninitialised,
+            registers: Vec::new(),
+        };
+        function.renumber();
+        function
+    }
+    fn new_block(&mut self) -> BlockId {
+        self.blocks.push(Block::empty());
+        BlockId(self.blocks.len() - 1)
+    }
+    fn new_temp(&mut self) -> Temp {
+        self.temps += 1;
+        Temp(self.temps - 1)
+    }
+    fn push(&mut self, operation: Operation) {
+        let block = &mut self.blocks[self.current];
+        block.operations.push(operation);
+        block.origins.push(self.origin.unwrap_or_default());
+    }
+    /// Ends the current block, `start` picks the block the next operations go to
+    fn terminate(&mut self, terminator: Terminator) {
+        self.blocks[self.current].terminator = terminator;
+        self.blocks[self.current].terminator_origin = self.origin;
+    }
+    fn start(&mut self, block: BlockId) {
+        self.current = block.0;
+    }
+    /// The name `referenced_variables` knows a variable of this body by
+    fn qualified(&self, name: &str) -> String {
+        match self.name {
+            Some(procedure) => format!("{name}@{procedure}"),
+            None => name.to_string(),
+        }
+    }
+    fn reference(&mut self, name: &str) {
+        let name = self.qualified(name);
+        self.checker.referenced_variables.insert(name);
+    }
+    fn lookup(&mut self, id: &Pidentifier) -> Option<Variable> {
+        let variable = self.scope.get(&id.0).copied();
+        if variable.is_none() {
+            self.checker.diagnostics.push(CompilerError::UndeclaredVariable(id.0.clone(), id.1));
+        }
+        variable
+    }
+    fn lower_commands(&mut self, commands: &Commands) {
+        for command in commands {
+            let parent = self.origin;
+            self.commands.push(SourceCommand { span: command.span(), depth: self.depth, loops: self.loops, parent });
+            self.origin = Some(self.commands.len() - 1);
+            let is_loop = matches!(command, Command::While(_, _, _) | Command::Repeat(_, _, _));
+            self.depth += 1;
+            self.loops += usize::from(is_loop);
+            self.lower_command(command);
+            self.depth -= 1;
+            self.loops -= usize::from(is_loop);
+            self.origin = parent;
+        }
+    }
+    fn lower_command(&mut self, command: &Command) {
+        match command {
+            Command::Assign(identifier, expression, _) => {
+                let value = self.lower_expression(expression);
+                if let Some(place) = self.place(identifier) {
+                    self.push(Operation::Store(place, value));
+                }
+            },
+            Command::If(condition, commands, else_commands, _) => {
+                let (relation, operand_0, operand_1) = self.lower_condition(condition);
+                let then_block = self.new_block();
+                let else_block = else_commands.as_ref().map(|_| self.new_block());
+                let join = self.new_block();
+                self.terminate(Terminator::Branch(relation, operand_0, operand_1, then_block, else_block.unwrap_or(join)));
+                self.start(then_block);
+                self.lower_commands(commands);
+                self.terminate(Terminator::Jump(join));
+                if let (Some(else_block), Some(else_commands)) = (else_block, else_commands) {
+                    self.start(else_block);
+                    self.lower_commands(else_commands);
+                    self.terminate(Terminator::Jump(join));
+                }
+                self.start(join);
+            },
+            Command::While(condition, commands, _) => {
+                let header = self.new_block();
+                self.terminate(Terminator::Jump(header));
+                self.start(header);
+                let (relation, operand_0, operand_1) = self.lower_condition(condition);
+                let (body, exit) = (self.new_block(), self.new_block());
+                self.terminate(Terminator::Branch(relation, operand_0, operand_1, body, exit));
+                self.start(body);
+                self.lower_commands(commands);
+                self.terminate(Terminator::Jump(header));
+                self.start(exit);
+            },
+            Command::Repeat(commands, condition, _) => {
+                let body = self.new_block();
+                self.terminate(Terminator::Jump(body));
+                self.start(body);
+                self.lower_commands(commands);
+                let (relation, operand_0, operand_1) = self.lower_condition(condition);
+                let exit = self.new_block();
+                self.terminate(Terminator::Branch(relation, operand_0, operand_1, exit, body));
+                self.start(exit);
+            },
+            Command::ProcCall((name, arguments), call_span) => self.lower_call(name, arguments, *call_span),
+            Command::Read(identifier, _) => {
+                if let Some(place) = self.place(identifier) {
+                    self.push(Operation::Read(place));
+                }
+            },
+            Command::Write(value, _) => {
+                let operand = self.lower_value(value);
+                self.push(Operation::Write(operand));
+            },
+        }
+    }
+    /// Checks the call against the procedure's head and the arguments against its parameters, only the first wrong argument is reported
+    fn lower_call(&mut self, name: &Pidentifier, arguments: &Arguments, call_span: Span) {
+        // Lowering these could go on forever, the arguments still count as used
+        if self.checker.rejected_calls.contains(&call_span) {
+            for argument in arguments {
+                self.reference(&argument.0);
+            }
+            return;
+        }
+        let Some(procedure) = self.checker.procedures.iter().find(|procedure| procedure.0 .0 .0 == name.0).copied() else {
+            self.checker.diagnostics.push(CompilerError::UndeclaredProcedure(name.0.clone(), name.1));
+            return;
+        };
+        let (_, declared_arguments, head_span) = &procedure.0;
+        if declared_arguments.len() != arguments.len() {
+            self.checker.diagnostics.push(CompilerError::WrongNumberOfArguments(name.0.clone(), call_span, declared_arguments.len(), *head_span));
+            return;
+        }
+        self.checker.called_procedures.insert(name.0.clone());
+        let mut variables: Vec<Variable> = Vec::new();
+        for (argument, declared_argument) in arguments.iter().zip(declared_arguments) {
+            self.reference(&argument.0);
+            let Some(variable) = self.lookup(argument) else {
+                return;
+            };
+            let error = match (declared_argument, variable) {
+                (ArgumentsDeclarationVariant::Base(id), Variable::Array(_) | Variable::ArrayParameter(_)) => {
+                    CompilerError::WrongArgumentType(id.0.clone(), argument.1, id.1)
+                },
+                (ArgumentsDeclarationVariant::Table(id, declaration_span), Variable::Scalar | Variable::ScalarParameter) => {
+                    CompilerError::WrongArgumentType(id.0.clone(), argument.1, *declaration_span)
+                },
+                _ => {
+                    variables.push(variable);
+                    continue;
+                },
+            };
+            self.checker.diagnostics.push(error);
+            return;
+        }
+        for (parameter, variable) in variables.into_iter().enumerate() {
+            let Some((required, index_name, index_span)) = self.checker.table_requirements.get(&(name.0.clone(), parameter)).cloned() else {
+                continue;
+            };
+            match (variable, self.name) {
+                (Variable::Array(size), _) if required > size => {
+                    self.checker.diagnostics.push(CompilerError::IndexOutOfBounds(index_name, index_span));
+                    return;
+                },
+                (Variable::ArrayParameter(caller_parameter), Some(caller)) => {
+                    self.checker.require_table_size(caller, caller_parameter, required, index_name, index_span);
+                },
+                _ => {},
+            }
+        }
+        self.push(Operation::Call(name.0.clone(), arguments.iter().map(|argument| argument.0.clone()).collect()));
+    }
+    fn lower_condition(&mut self, condition: &Condition) -> (Relation, Operand, Operand) {
+        let (relation, value_0, value_1) = match condition {
+            Condition::Equal(value_0, value_1, _) => (Relation::Equal, value_0, value_1),
+            Condition::NotEqual(value_0, value_1, _) => (Relation::NotEqual, value_0, value_1),
+            Condition::Greater(value_0, value_1, _) => (Relation::Greater, value_0, value_1),
+            Condition::Lower(value_0, value_1, _) => (Relation::Lower, value_0, value_1),
+            Condition::GreaterOrEqual(value_0, value_1, _) => (Relation::GreaterOrEqual, value_0, value_1),
+            Condition::LowerOrEqual(value_0, value_1, _) => (Relation::LowerOrEqual, value_0, value_1),
+        };
+        (relation, self.lower_value(value_0), self.lower_value(value_1))
+    }
+    fn lower_expression(&mut self, expression: &Expression) -> Operand {
+        let (op, value_0, value_1) = match expression {
+            Expression::Val(value, _) => return self.lower_value(value),
+            Expression::Add(value_0, value_1, _) => (BinaryOp::Add, value_0, value_1),
+            Expression::Substract(value_0, value_1, _) => (BinaryOp::Sub, value_0, value_1),
+            Expression::Multiply(value_0, value_1, _) => (BinaryOp::Mul, value_0, value_1),
+            Expression::Divide(value_0, value_1, _) => (BinaryOp::Div, value_0, value_1),
+            Expression::Modulo(value_0, value_1, _) => (BinaryOp::Mod, value_0, value_1),
+        };
+        let operand_0 = self.lower_value(value_0);
+        let operand_1 = self.lower_value(value_1);
+        let temp = self.new_temp();
+        self.push(Operation::Binary(temp, op, operand_0, operand_1));
+        Operand::Temp(temp)
+    }
+    /// Constants stay operands, variables are loaded into a temporary. A wrong variable gives 0.
+    fn lower_value(&mut self, value: &Value) -> Operand {
+        match value {
+            Value::Num(num, _) => Operand::Const(num.clone()),
+            Value::Id(identifier) => match self.place(identifier) {
+                Some(place) => {
+                    let temp = self.new_temp();
+                    self.push(Operation::Load(temp, place));
+                    Operand::Temp(temp)
+                },
+                None => Operand::Const(Num::zero()),
+            },
+        }
+    }
+    /// The place `identifier` names, `None` once its error is recorded. Indices of arrays are checked before the arrays.
+    fn place(&mut self, identifier: &Identifier) -> Option<Place> {
+        match identifier {
+            Identifier::Base(id) => {
+                self.reference(&id.0);
+                match self.lookup(id)? {
+                    Variable::Scalar | Variable::ScalarParameter => Some(Place::Variable(id.0.clone())),
+                    Variable::Array(_) | Variable::ArrayParameter(_) => {
+                        self.checker.diagnostics.push(CompilerError::IncorrectUseOfVariable(id.0.clone(), id.1));
+                        None
+                    },
+                }
+            },
+            Identifier::NumIndexed(id, num, span) => {
+                self.reference(&id.0);
+                let index = num.to_u64();
+                match self.lookup(id)? {
+                    Variable::Scalar | Variable::ScalarParameter => {
+                        self.checker.diagnostics.push(CompilerError::IncorrectUseOfVariable(id.0.clone(), id.1));
+                        return None;
+                    },
+                    Variable::Array(size) if index.is_none_or(|index| index >= size) => {
+                        self.checker.diagnostics.push(CompilerError::IndexOutOfBounds(id.0.clone(), *span));
+                        return None;
+                    },
+                    Variable::Array(_) => {},
+                    // The size is checked at every call, against the largest index
+                    Variable::ArrayParameter(parameter) => {
+                        let Some(index) = index.filter(|index| *index < u64::MAX) else {
+                            self.checker.diagnostics.push(CompilerError::IndexOutOfBounds(id.0.clone(), *span));
+                            return None;
+                        };
+                        if let Some(procedure) = self.name {
+                            self.checker.require_table_size(procedure, parameter, index + 1, id.0.clone(), *span);
+                        }
+                    },
+                }
+                Some(Place::Element(id.0.clone(), Operand::Const(num.clone())))
+            },
+            Identifier::PidIndexed(id, index_id, _) => {
+                self.reference(&id.0);
+                self.reference(&index_id.0);
+                if let Variable::Array(_) | Variable::ArrayParameter(_) = self.lookup(index_id)? {
+                    self.checker.diagnostics.push(CompilerError::ArrayUsedAsIndex(index_id.0.clone(), index_id.1));
+                    return None;
+                }
+                if let Variable::Scalar | Variable::ScalarParameter = self.lookup(id)? {
+                    self.checker.diagnostics.push(CompilerError::IncorrectUseOfVariable(id.0.clone(), id.1));
+                    return None;
+                }
+                let index = self.new_temp();
+                self.push(Operation::Load(index, Place::Variable(index_id.0.clone())));
+                Some(Place::Element(id.0.clone(), Operand::Temp(index)))
+            },
+        }
+    }
+}
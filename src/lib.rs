@@ -0,0 +1,178 @@
+//! The gembalang compiler as a library, `compile` turns source into code for the virtual machine
+
+mod analysis;
+pub mod assembler;
+pub mod ast;
+mod callgraph;
+pub mod diagnostics;
+pub mod dump;
+pub mod format;
+mod inline;
+pub mod ir;
+pub mod passes;
+pub mod peephole;
+mod reduce;
+pub mod symbols;
+pub mod vm;
+
+use lalrpop_util::lalrpop_mod;
+
+lalrpop_mod!(#[allow(clippy::all)] pub lexparse);
+
+use assembler::{CostReport, Instruction};
+use ast::Program;
+use diagnostics::{Diagnostic, Severity, WarningOptions};
+use ir::Module;
+use passes::{Pass, PassOptions};
+use peephole::PeepholeOptions;
+
+/// How hard to optimise, `Os` prefers short code over fast code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OptLevel {
+    O0,
+    O1,
+    #[default]
+    O2,
+    Os,
+}
+
+impl OptLevel {
+    /// Parses the part after `--opt-level=` or `-O`
+    pub fn from_name(name: &str) -> Option<OptLevel> {
+        match name {
+            "0" => Some(OptLevel::O0),
+            "1" => Some(OptLevel::O1),
+            "2" => Some(OptLevel::O2),
+            "s" => Some(OptLevel::Os),
+            _ => None,
+        }
+    }
+}
+
+/// Settings of a single compilation
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions {
+    pub warnings: WarningOptions,
+    pub opt_level: OptLevel,
+    pub passes: PassOptions,
+    pub peephole: PeepholeOptions,
+}
+
+/// Result of a successful compilation
+#[derive(Debug, Clone)]
+pub struct CompiledProgram {
+    pub ast: Program,
+    /// The IR the code is built from, as it is after the passes
+    pub ir: Module,
+    /// Instructions with jumps to labels and unexpanded macro instructions
+    pub pseudo_assembly: Vec<Instruction>,
+    /// Final code for the virtual machine
+    pub assembly: String,
+    /// Warnings that are enabled and not denied, sorted by position
+    pub warnings: Vec<Diagnostic>,
+    /// Static costs of the code, see `cost_report`
+    pub costs: CostReport,
+    /// After each pass asked for with `PassOptions::print_after`, the IR or for the passes over the code the pseudo-assembly
+    pub after_passes: Vec<(Pass, String)>,
+}
+
+impl CompiledProgram {
+    /// The final code with the address of every instruction
+    pub fn listing(&self) -> String {
+        let lines: Vec<&str> = self.assembly.lines().collect();
+        let width = lines.len().saturating_sub(1).to_string().len();
+        lines.iter().enumerate().map(|(address, line)| format!("{address:>width$}  {line}\n")).collect()
+    }
+    /// The static cost of every command, procedure body and of the whole program, each instruction counted once.
+    /// `*` marks commands once for every loop around them, which are counted before the peephole rules.
+    /// `source` is the program this was compiled from.
+    pub fn cost_report(&self, source: &str) -> String {
+        let mut report = String::new();
+        for body in &self.costs.bodies {
+            match &body.procedure {
+                Some(name) => report += &format!("procedure {name}: {}\n", body.cost),
+                None => report += &format!("main: {}\n", body.cost),
+            }
+            report += "   line      cost  loop  command\n";
+            for command in &body.commands {
+                let line = source[..command.span.start].matches('\n').count() + 1;
+                let text = source[command.span.start..command.span.end].lines().next().unwrap_or_default().trim_end();
+                let loops = "*".repeat(command.loops);
+                let indent = "  ".repeat(command.depth);
+                report += &format!("{line:>7}  {:>8}  {loops:<4}  {indent}{text}\n", command.cost);
+            }
+        }
+        if !self.costs.inlined.is_empty() {
+            report += &format!("inlined into every call: {}\n", self.costs.inlined.join(", "));
+        }
+        report += &format!("program: {}\n", self.costs.total);
+        report
+    }
+}
+
+/// Everything reported by a failed compilation, errors and warnings sorted by position
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    diagnostics: Vec<Diagnostic>,
+    syntax_error: bool,
+}
+
+impl Diagnostics {
+    /// Whether the program could not even be parsed
+    pub fn is_syntax_error(&self) -> bool {
+        self.syntax_error
+    }
+    pub fn iter(&self) -> std::slice::Iter<'_, Diagnostic> {
+        self.diagnostics.iter()
+    }
+    pub fn errors(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics.iter().filter(|diagnostic| diagnostic.severity == Severity::Error)
+    }
+    pub fn into_vec(self) -> Vec<Diagnostic> {
+        self.diagnostics
+    }
+}
+
+impl<'a> IntoIterator for &'a Diagnostics {
+    type Item = &'a Diagnostic;
+    type IntoIter = std::slice::Iter<'a, Diagnostic>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.diagnostics.iter()
+    }
+}
+
+/// Parses the program, lowers it to the IR and builds it. Fails on any error, denied warnings included.
+pub fn compile(source: &str, options: &CompileOptions) -> Result<CompiledProgram, Diagnostics> {
+    let ast = parse(source).map_err(|error| Diagnostics { diagnostics: vec![error], syntax_error: true })?;
+    let (result, warnings) = ir::lower(&ast);
+    let mut diagnostics: Vec<Diagnostic> =
+        warnings.iter().filter_map(|warning| Diagnostic::from_compiler_warning(warning, &options.warnings)).collect();
+    let denied_warnings = diagnostics.iter().any(|diagnostic| diagnostic.severity == Severity::Error);
+    let module = match result {
+        Ok(_) if denied_warnings => return Err(Diagnostics { diagnostics, syntax_error: false }),
+        Ok(module) => module,
+        Err(errors) => {
+            diagnostics.extend(errors.iter().map(Diagnostic::from_compiler_error));
+            diagnostics.sort_by_key(|diagnostic| diagnostic.span.map(|span| span.start));
+            return Err(Diagnostics { diagnostics, syntax_error: false });
+        },
+    };
+    let pipeline = options.passes.pipeline(options.opt_level);
+    let (ir, assembler, after_passes) = pipeline.run(module, options, options.passes.printed_after());
+    Ok(CompiledProgram {
+        ir,
+        pseudo_assembly: assembler.pseudo_assembly(),
+        assembly: assembler.assemble(),
+        warnings: diagnostics,
+        costs: assembler.cost_report(),
+        after_passes,
+        ast,
+    })
+}
+
+/// Only parses the program, a syntax error comes back as a diagnostic
+pub fn parse(source: &str) -> Result<Program, Diagnostic> {
+    lexparse::ProgramParser::new()
+        .parse(source)
+        .map_err(|error| Diagnostic::from_parse_error(&error, source))
+}
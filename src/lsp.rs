@@ -0,0 +1,262 @@
+//! Language server for gembalang, speaking LSP over stdio
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use gembalang::diagnostics::{Diagnostic, Severity};
+use gembalang::symbols::{Symbol, SymbolIndex, SymbolKind};
+use gembalang::{compile, parse, CompileOptions};
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _, PublishDiagnostics,
+};
+use lsp_types::request::{Completion, DocumentSymbolRequest, GotoDefinition, HoverRequest, References, Request as _};
+use lsp_types::*;
+
+/// An open file, with the symbols of its last version that parsed
+struct Document {
+    text: String,
+    index: SymbolIndex,
+    /// Whether `index` belongs to `text`, a stale one is only good enough for completion
+    index_is_current: bool,
+}
+
+impl Document {
+    fn symbol_at(&self, position: Position) -> Option<&Symbol> {
+        self.index_is_current.then(|| self.index.symbol_at(offset(&self.text, position))).flatten()
+    }
+    fn location(&self, uri: &Uri, span: gembalang::ast::Span) -> Location {
+        Location { uri: uri.clone(), range: range(&self.text, span) }
+    }
+}
+
+fn main() {
+    if let Err(error) = serve() {
+        eprintln!("error: {error}");
+        std::process::exit(1);
+    }
+}
+
+fn serve() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        references_provider: Some(OneOf::Left(true)),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        ..ServerCapabilities::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+    // Keyed by the URI's text, `Uri` itself is not a good key
+    let mut documents: HashMap<String, Document> = HashMap::new();
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    break;
+                }
+                connection.sender.send(Message::Response(respond(&documents, request)))?;
+            },
+            Message::Notification(notification) => {
+                if let Some(published) = update(&mut documents, notification) {
+                    connection.sender.send(Message::Notification(published))?;
+                }
+            },
+            Message::Response(_) => {},
+        }
+    }
+    // The writer thread stops once the connection is gone
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+/// Keeps the open documents up to date, returns their new diagnostics
+fn update(documents: &mut HashMap<String, Document>, notification: Notification) -> Option<Notification> {
+    let (uri, text) = match notification.method.as_str() {
+        DidOpenTextDocument::METHOD => {
+            let params: DidOpenTextDocumentParams = serde_json::from_value(notification.params).ok()?;
+            (params.text_document.uri, params.text_document.text)
+        },
+        // Only full text is asked for, so the last change is the whole document
+        DidChangeTextDocument::METHOD => {
+            let params: DidChangeTextDocumentParams = serde_json::from_value(notification.params).ok()?;
+            (params.text_document.uri, params.content_changes.into_iter().last()?.text)
+        },
+        DidCloseTextDocument::METHOD => {
+            let params: DidCloseTextDocumentParams = serde_json::from_value(notification.params).ok()?;
+            documents.remove(params.text_document.uri.as_str());
+            return Some(publish(params.text_document.uri, Vec::new()));
+        },
+        _ => return None,
+    };
+    let diagnostics = match compile(&text, &CompileOptions::default()) {
+        Ok(program) => program.warnings,
+        Err(diagnostics) => diagnostics.into_vec(),
+    };
+    let diagnostics = diagnostics.iter().map(|diagnostic| to_lsp_diagnostic(&uri, &text, diagnostic)).collect();
+    let document = match parse(&text) {
+        Ok(ast) => Document { index: SymbolIndex::new(&ast), text, index_is_current: true },
+        Err(_) => {
+            let index = documents.remove(uri.as_str()).map(|document| document.index).unwrap_or_default();
+            Document { index, text, index_is_current: false }
+        },
+    };
+    documents.insert(uri.to_string(), document);
+    Some(publish(uri, diagnostics))
+}
+
+fn publish(uri: Uri, diagnostics: Vec<lsp_types::Diagnostic>) -> Notification {
+    Notification::new(PublishDiagnostics::METHOD.to_string(), PublishDiagnosticsParams { uri, diagnostics, version: None })
+}
+
+fn to_lsp_diagnostic(uri: &Uri, text: &str, diagnostic: &Diagnostic) -> lsp_types::Diagnostic {
+    let mut message = diagnostic.message.clone();
+    let mut related_information = Vec::new();
+    for note in &diagnostic.notes {
+        match note.span {
+            Some(span) => related_information.push(DiagnosticRelatedInformation {
+                location: Location { uri: uri.clone(), range: range(text, span) },
+                message: note.message.clone(),
+            }),
+            None => message.push_str(&format!("\nnote: {}", note.message)),
+        }
+    }
+    lsp_types::Diagnostic {
+        range: diagnostic.span.map(|span| range(text, span)).unwrap_or_default(),
+        severity: Some(match diagnostic.severity {
+            Severity::Error => DiagnosticSeverity::ERROR,
+            Severity::Warning => DiagnosticSeverity::WARNING,
+        }),
+        code: Some(NumberOrString::String(diagnostic.code.to_string())),
+        source: Some("gembalang".to_string()),
+        message,
+        related_information: (!related_information.is_empty()).then_some(related_information),
+        ..lsp_types::Diagnostic::default()
+    }
+}
+
+fn respond(documents: &HashMap<String, Document>, request: Request) -> Response {
+    let id = request.id;
+    let result = match request.method.as_str() {
+        GotoDefinition::METHOD => serde_json::from_value(request.params).map(|params: GotoDefinitionParams| {
+            let position = params.text_document_position_params;
+            let uri = position.text_document.uri;
+            let document = documents.get(uri.as_str());
+            let symbol = document.and_then(|document| document.symbol_at(position.position));
+            let location = document.zip(symbol).map(|(document, symbol)| document.location(&uri, symbol.span));
+            serde_json::to_value(location.map(GotoDefinitionResponse::Scalar))
+        }),
+        HoverRequest::METHOD => serde_json::from_value(request.params).map(|params: HoverParams| {
+            let position = params.text_document_position_params;
+            let document = documents.get(position.text_document.uri.as_str());
+            let hover = document.and_then(|document| document.symbol_at(position.position)).map(|symbol| Hover {
+                contents: HoverContents::Markup(MarkupContent { kind: MarkupKind::Markdown, value: symbol.to_string() }),
+                range: None,
+            });
+            serde_json::to_value(hover)
+        }),
+        References::METHOD => serde_json::from_value(request.params).map(|params: ReferenceParams| {
+            let position = params.text_document_position;
+            let uri = position.text_document.uri;
+            let mut locations = Vec::new();
+            if let Some(document) = documents.get(uri.as_str()) {
+                if let Some(symbol) = document.symbol_at(position.position) {
+                    if params.context.include_declaration {
+                        locations.push(document.location(&uri, symbol.span));
+                    }
+                    locations.extend(symbol.references.iter().map(|span| document.location(&uri, *span)));
+                }
+            }
+            serde_json::to_value(locations)
+        }),
+        DocumentSymbolRequest::METHOD => serde_json::from_value(request.params).map(|params: DocumentSymbolParams| {
+            let symbols: Vec<DocumentSymbol> = documents
+                .get(params.text_document.uri.as_str())
+                .filter(|document| document.index_is_current)
+                .map(|document| document.index.procedures().map(|procedure| document_symbol(&document.text, procedure)).collect())
+                .unwrap_or_default();
+            serde_json::to_value(DocumentSymbolResponse::Nested(symbols))
+        }),
+        Completion::METHOD => serde_json::from_value(request.params).map(|params: CompletionParams| {
+            let position = params.text_document_position;
+            let items: Vec<CompletionItem> = documents
+                .get(position.text_document.uri.as_str())
+                .map(|document| {
+                    let offset = offset(&document.text, position.position);
+                    document.index.visible_at(offset).into_iter().map(completion_item).collect()
+                })
+                .unwrap_or_default();
+            serde_json::to_value(CompletionResponse::Array(items))
+        }),
+        _ => return Response::new_err(id, ErrorCode::MethodNotFound as i32, format!("unsupported request `{}`", request.method)),
+    };
+    match result {
+        Ok(Ok(value)) => Response { id, result: Some(value), error: None },
+        Ok(Err(error)) => Response::new_err(id, ErrorCode::InternalError as i32, error.to_string()),
+        Err(error) => Response::new_err(id, ErrorCode::InvalidParams as i32, error.to_string()),
+    }
+}
+
+fn document_symbol(text: &str, procedure: &Symbol) -> DocumentSymbol {
+    #[allow(deprecated)]
+    DocumentSymbol {
+        name: procedure.name.clone(),
+        detail: Some(procedure.to_string()),
+        kind: lsp_types::SymbolKind::FUNCTION,
+        tags: None,
+        deprecated: None,
+        range: range(text, procedure.scope),
+        selection_range: range(text, procedure.span),
+        children: None,
+    }
+}
+
+fn completion_item(symbol: &Symbol) -> CompletionItem {
+    CompletionItem {
+        label: symbol.name.clone(),
+        kind: Some(match symbol.kind {
+            SymbolKind::Procedure(_) => CompletionItemKind::FUNCTION,
+            _ => CompletionItemKind::VARIABLE,
+        }),
+        detail: Some(symbol.to_string()),
+        ..CompletionItem::default()
+    }
+}
+
+/// LSP positions count UTF-16 code units within a line
+fn position(text: &str, offset: usize) -> Position {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map(|newline| newline + 1).unwrap_or(0);
+    Position {
+        line: before.matches('\n').count() as u32,
+        character: before[line_start..].encode_utf16().count() as u32,
+    }
+}
+
+/// Byte offset of `position`, positions past the end of a line are at its end
+fn offset(text: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match text[line_start..].find('\n') {
+            Some(newline) => line_start += newline + 1,
+            None => return text.len(),
+        }
+    }
+    let line = &text[line_start..];
+    let line = &line[..line.find('\n').unwrap_or(line.len())];
+    let mut units = 0;
+    for (index, character) in line.char_indices() {
+        if units >= position.character as usize {
+            return line_start + index;
+        }
+        units += character.len_utf16();
+    }
+    line_start + line.len()
+}
+
+fn range(text: &str, span: gembalang::ast::Span) -> Range {
+    Range { start: position(text, span.start), end: position(text, span.end) }
+}
@@ -1,102 +1,162 @@
-mod assembler;
-mod ast;
-
-use lalrpop_util::lalrpop_mod;
-
-lalrpop_mod!(pub lexparse);
+mod cli;
 
 use std::env;
 use std::fs;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{self, IsTerminal, Read, Write};
+
+use cli::*;
+use gembalang::assembler::CompilerError;
+use gembalang::diagnostics::*;
+use gembalang::{compile, dump, format, parse, vm, CompiledProgram};
 
-use assembler::*;
+const EXIT_SEMANTIC_ERROR: i32 = 1;
+const EXIT_SYNTAX_ERROR: i32 = 2;
+const EXIT_IO_ERROR: i32 = 3;
+const EXIT_USAGE_ERROR: i32 = 4;
+const EXIT_RUNTIME_ERROR: i32 = 5;
+const EXIT_NOT_FORMATTED: i32 = 6;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() == 2 {
-        panic!("Supply 2 argumments");
+    let args: Vec<String> = env::args_os().skip(1).map(|arg| arg.to_string_lossy().into_owned()).collect();
+    let cli = match parse_args(&args) {
+        Ok(Action::Compile(cli)) => *cli,
+        Ok(Action::Help) => {
+            print!("{USAGE}");
+            return;
+        },
+        Ok(Action::Version) => {
+            println!("kompilator {}", env!("CARGO_PKG_VERSION"));
+            return;
+        },
+        Err(message) => exit_with_error(CompilerError::InvalidCommandLine(message), "", error_format(&args), EXIT_USAGE_ERROR),
+    };
+    let source_name = if cli.input == "-" { "<stdin>" } else { cli.input.as_str() };
+    let compilee = match read_input(&cli.input) {
+        Ok(compilee) => compilee,
+        Err(error) => exit_with_error(CompilerError::UnreadableFile(source_name.to_string(), error.to_string()), source_name, cli.error_format, EXIT_IO_ERROR),
+    };
+    let source_file = SourceFile::new(source_name, &compilee);
+
+    if cli.subcommand == Subcommand::Fmt {
+        let formatted = match format::format(&compilee) {
+            Ok(formatted) => formatted,
+            Err(diagnostic) => {
+                // The only error that is not a syntax error is the formatter breaking the program
+                let exit_code = if diagnostic.span.is_some() { EXIT_SYNTAX_ERROR } else { EXIT_SEMANTIC_ERROR };
+                write_messages(&[diagnostic], &source_file, cli.error_format);
+                std::process::exit(exit_code);
+            },
+        };
+        if !cli.check {
+            write_or_exit(cli.output.as_deref(), &formatted, source_name, cli.error_format);
+        } else if formatted != compilee {
+            exit_with_error(CompilerError::NotFormatted(source_name.to_string()), source_name, cli.error_format, EXIT_NOT_FORMATTED);
+        }
+        return;
     }
-    let input_file_path = args.get(1).unwrap();
-    let output_file_path = args.get(2).unwrap();
-    let compilee = fs::read_to_string(input_file_path).unwrap();
 
-    let parser_output = lexparse::ProgramParser::new().parse(&compilee);
-    match parser_output {
-        Ok(ast) => {
-            match Assembler::new(ast) {
-                Ok(mut pseudo_assembler) => {
-                    match pseudo_assembler.construct() {
-                        Ok(_) => {
-                            let ass = pseudo_assembler.assemble();
-                            fs::write(output_file_path, ass).expect("Unable to write to file");
-                        },
-                        Err(error) => write_message_and_exit(error, input_file_path),
-                    }
-                },
-                Err(error) => write_message_and_exit(error, input_file_path),
+    // The AST is dumped even when the program has semantic errors, to help with debugging the grammar
+    if cli.subcommand == Subcommand::Build && matches!(cli.emit, Emit::Ast | Emit::AstJson) {
+        let ast = match parse(&compilee) {
+            Ok(ast) => ast,
+            Err(diagnostic) => {
+                write_messages(&[diagnostic], &source_file, cli.error_format);
+                std::process::exit(EXIT_SYNTAX_ERROR);
+            },
+        };
+        let dumped = if cli.emit == Emit::Ast { dump::ast_tree(&ast) } else { dump::ast_json(&ast) };
+        write_or_exit(cli.output.as_deref(), &dumped, source_name, cli.error_format);
+        return;
+    }
+
+    let program = match compile(&compilee, &cli.options) {
+        Ok(program) => {
+            write_messages(&program.warnings, &source_file, cli.error_format);
+            if cli.cost_report {
+                eprint!("{}", program.cost_report(&compilee));
+            }
+            for (pass, printed) in &program.after_passes {
+                eprintln!("# after {}", pass.name());
+                eprint!("{printed}");
             }
+            program
         },
-        Err(_) => {
-            println!("Syntax Error")
+        Err(diagnostics) => {
+            let exit_code = if diagnostics.is_syntax_error() { EXIT_SYNTAX_ERROR } else { EXIT_SEMANTIC_ERROR };
+            write_messages(&diagnostics.into_vec(), &source_file, cli.error_format);
+            std::process::exit(exit_code);
         },
     };
+    match cli.subcommand {
+        // `fmt` is done before compiling
+        Subcommand::Check | Subcommand::Fmt => {},
+        Subcommand::Build => write_or_exit(cli.output.as_deref(), &emitted(&program, cli.emit), source_name, cli.error_format),
+        Subcommand::Run => {
+            let result = vm::run(&program.assembly, &mut io::stdin().lock(), &mut io::stdout().lock());
+            match result {
+                Ok(execution) => eprintln!("cost: {} (i/o: {})", execution.cost, execution.io_cost),
+                Err(error) => {
+                    eprintln!("error: {error}");
+                    std::process::exit(EXIT_RUNTIME_ERROR);
+                },
+            }
+        },
+    }
 }
 
-fn write_message_and_exit(error: CompilerError, input_file_path: &String) {
-    let line_no = find_line_number(input_file_path, error.get_byte()).unwrap();
-    match error {
-        CompilerError::UndeclaredVariable(mut id, _) => {
-            id = id.split('@').next().unwrap().to_string();
-            println!("ERROR: Undeclared variable `{id}` line: {line_no}");
-        },
-        CompilerError::UndeclaredProcedure(id, _) => {
-            println!("ERROR: Undeclared procedure `{id}` line: {line_no}");
-        },
-        CompilerError::IncorrectUseOfVariable(mut id, _) => {
-            id = id.split('@').next().unwrap().to_string();
-            println!("ERROR: Undeclared variable `{id}` line: {line_no}");
-        },
-        CompilerError::IndexOutOfBounds(mut id, _) => {
-            id = id.split('@').next().unwrap().to_string();
-            println!("ERROR: Index out of bounds for variable `{id}` line: {line_no}");
-        },
-        CompilerError::ArrayUsedAsIndex(mut id, _) => {
-            id = id.split('@').next().unwrap().to_string();
-            println!("ERROR: Array used as index for variable `{id}` line: {line_no}");
-        },
-        CompilerError::WrongArgumentType(mut id, _) => {
-            id = id.split('@').next().unwrap().to_string();
-            println!("ERROR: Wrong argument type for variable `{id}` line: {line_no}");
-        },
-        CompilerError::DuplicateVariableDeclaration(mut id, _) => {
-            id = id.split('@').next().unwrap().to_string();
-            println!("ERROR: Duplicate variable declaration for variable `{id}` line: {line_no}");
-        },
-        CompilerError::DuplicateProcedureDeclaration(id, _) => {
-            println!("ERROR: Duplicate procedure declaration for procedure `{id}` line: {line_no}");
-        },
-        CompilerError::RecursiveProcedureCall(id, _) => {
-            println!("ERROR: Recursive procedure call for procedure `{id}` line: {line_no}");
-        },
-        CompilerError::WrongNumberOfArguments(id, _) => {
-            println!("ERROR: Wrong number of arguments for procedure `{id}` line: {line_no}");
-        },
+fn emitted(program: &CompiledProgram, emit: Emit) -> String {
+    match emit {
+        Emit::Asm => program.assembly.clone(),
+        Emit::Listing => program.listing(),
+        Emit::Pseudo => program.pseudo_assembly.iter().map(|instruction| format!("{instruction}\n")).collect(),
+        Emit::Ir => program.ir.to_string(),
+        Emit::Ast => dump::ast_tree(&program.ast),
+        Emit::AstJson => dump::ast_json(&program.ast),
+    }
+}
+
+/// `-` is stdin
+fn read_input(path: &str) -> io::Result<String> {
+    if path == "-" {
+        let mut source = String::new();
+        io::stdin().read_to_string(&mut source)?;
+        return Ok(source);
+    }
+    fs::read_to_string(path)
+}
+
+/// `None` and `-` are stdout
+fn write_or_exit(path: Option<&str>, contents: &str, source_name: &str, error_format: ErrorFormat) {
+    let path = path.unwrap_or("-");
+    if let Err(error) = write_output(path, contents) {
+        let path = if path == "-" { "<stdout>" } else { path };
+        exit_with_error(CompilerError::UnwritableFile(path.to_string(), error.to_string()), source_name, error_format, EXIT_IO_ERROR);
     }
-    std::process::exit(1);
 }
 
-fn find_line_number(file_path: &str, n: usize) -> Option<usize> {
-    let file = File::open(file_path).unwrap();
-    let reader = BufReader::new(file);
+/// `-` is stdout
+fn write_output(path: &str, contents: &str) -> io::Result<()> {
+    if path == "-" {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(contents.as_bytes())?;
+        return stdout.flush();
+    }
+    fs::write(path, contents)
+}
+
+/// Reports an error that has no place in the source, then exits. `source_name` is empty before the input is known.
+fn exit_with_error(error: CompilerError, source_name: &str, error_format: ErrorFormat, exit_code: i32) -> ! {
+    write_messages(&[Diagnostic::from_compiler_error(&error)], &SourceFile::new(source_name, ""), error_format);
+    std::process::exit(exit_code);
+}
 
-    let mut total_bytes = 0;
-    for (i, line) in reader.lines().enumerate() {
-        let line = line.unwrap();
-        total_bytes += line.len() + 1; // +1 for the '\n' character
-        if total_bytes >= n {
-            return Some(i + 1); // +1 because line numbers start from 1
+/// Diagnostics go to stderr, so that they never mix with output written to stdout
+fn write_messages(diagnostics: &[Diagnostic], source_file: &SourceFile, error_format: ErrorFormat) {
+    let colour = io::stderr().is_terminal();
+    for diagnostic in diagnostics {
+        match error_format {
+            ErrorFormat::Human => eprintln!("{}", render(diagnostic, source_file, colour)),
+            ErrorFormat::Json => eprintln!("{}", render_json(diagnostic, source_file)),
         }
     }
-    None// return None if n is greater than the total number of bytes
 }
@@ -0,0 +1,185 @@
+use std::collections::HashSet;
+
+use crate::assembler::{allocate_registers, called_procedures, Assembler};
+use crate::inline::{inline, Strategy};
+use crate::ir::Module;
+use crate::peephole;
+use crate::reduce::reduce;
+use crate::{CompileOptions, OptLevel};
+
+/// An optimisation, `PASSES` lists them in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pass {
+    Inline,
+    Reduce,
+    Registers,
+    Peephole,
+    ShareRoutines,
+}
+
+/// What a pass rewrites
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// The IR, before the code is built from it
+    Ir,
+    /// The pseudo-assembly built from the IR
+    Code,
+}
+
+/// Every pass, in the order they run whatever order they are given in
+pub const PASSES: [Pass; 5] = [Pass::Inline, Pass::Reduce, Pass::Registers, Pass::Peephole, Pass::ShareRoutines];
+
+impl Pass {
+    pub fn name(self) -> &'static str {
+        match self {
+            Pass::Inline => "inline",
+            Pass::Reduce => "reduce",
+            Pass::Registers => "registers",
+            Pass::Peephole => "peephole",
+            Pass::ShareRoutines => "share-routines",
+        }
+    }
+    pub fn stage(self) -> Stage {
+        match self {
+            Pass::Inline | Pass::Reduce | Pass::Registers => Stage::Ir,
+            Pass::Peephole | Pass::ShareRoutines => Stage::Code,
+        }
+    }
+    /// Whether the pass runs again as long as it changes something
+    pub fn fixed_point(self) -> bool {
+        matches!(self, Pass::Reduce | Pass::Peephole)
+    }
+    /// Runs a pass of `Stage::Ir` of `pipeline` once, returns whether it changed anything
+    fn run_on_ir(self, module: &mut Module, pipeline: &Pipeline, options: &CompileOptions) -> bool {
+        match self {
+            Pass::Inline => {
+                let strategy = match options.opt_level {
+                    OptLevel::Os => Strategy::Shorter,
+                    OptLevel::O0 | OptLevel::O1 | OptLevel::O2 => Strategy::Faster { registers: pipeline.runs(Pass::Registers) },
+                };
+                inline(module, strategy)
+            },
+            Pass::Reduce => module.functions.iter_mut().map(reduce).filter(|changed| *changed).count() > 0,
+            Pass::Registers => {
+                module.functions.iter_mut().for_each(allocate_registers);
+                false
+            },
+            Pass::Peephole | Pass::ShareRoutines => false,
+        }
+    }
+    /// Runs a pass of `Stage::Code` once, returns whether it changed anything
+    fn run_on_code(self, assembler: &mut Assembler, options: &CompileOptions) -> bool {
+        match self {
+            // The jumps of a body stay within it, calls go through `JUMPR`
+            Pass::Peephole => assembler.bodies_mut().fold(false, |changed, body| match peephole::optimise_once(body, &options.peephole) {
+                Some(optimised) => {
+                    *body = optimised;
+                    true
+                },
+                None => changed,
+            }),
+            Pass::ShareRoutines => {
+                assembler.share_routines(options.opt_level);
+                false
+            },
+            Pass::Inline | Pass::Reduce | Pass::Registers => false,
+        }
+    }
+    /// Parses the name of a pass, returns an error message for unknown ones
+    pub fn from_name(name: &str) -> Result<Pass, String> {
+        PASSES.into_iter().find(|pass| pass.name() == name).ok_or_else(|| {
+            let known: Vec<&str> = PASSES.iter().map(|pass| pass.name()).collect();
+            format!("unknown pass `{name}`, expected one of: {}", known.join(", "))
+        })
+    }
+}
+
+/// The passes a compilation runs, always in the order of `PASSES`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pipeline {
+    passes: Vec<Pass>,
+}
+
+impl Pipeline {
+    /// `-O0` only inlines, `-O1` leaves the variables in memory, `-O2` and `-Os` run every pass
+    pub fn for_level(opt_level: OptLevel) -> Pipeline {
+        match opt_level {
+            OptLevel::O0 => Pipeline::new(&[Pass::Inline]),
+            OptLevel::O1 => Pipeline::new(&[Pass::Inline, Pass::Reduce, Pass::Peephole, Pass::ShareRoutines]),
+            OptLevel::O2 | OptLevel::Os => Pipeline::new(&PASSES),
+        }
+    }
+    pub fn new(passes: &[Pass]) -> Pipeline {
+        Pipeline { passes: PASSES.into_iter().filter(|pass| passes.contains(pass)).collect() }
+    }
+    pub fn passes(&self) -> &[Pass] {
+        &self.passes
+    }
+    pub fn runs(&self, pass: Pass) -> bool {
+        self.passes.contains(&pass)
+    }
+    /// Runs the passes over the IR, builds the code from it and runs the passes over the code.
+    /// A fixed-point pass runs until it changes nothing. Returns the IR and the code as they end up,
+    /// with the IR or the pseudo-assembly as it is after each pass of `printed`.
+    pub(crate) fn run(&self, mut module: Module, options: &CompileOptions, printed: &[Pass]) -> (Module, Assembler, Vec<(Pass, String)>) {
+        let mut after_passes: Vec<(Pass, String)> = Vec::new();
+        let used = called_procedures(&module);
+        for pass in self.passes.iter().filter(|pass| pass.stage() == Stage::Ir) {
+            while pass.run_on_ir(&mut module, self, options) && pass.fixed_point() {}
+            if printed.contains(pass) {
+                after_passes.push((*pass, module.to_string()));
+            }
+        }
+        let mut assembler = Assembler::new(&module, &used);
+        for pass in self.passes.iter().filter(|pass| pass.stage() == Stage::Code) {
+            while pass.run_on_code(&mut assembler, options) && pass.fixed_point() {}
+            if printed.contains(pass) {
+                after_passes.push((*pass, assembler.pseudo_assembly().iter().map(|instruction| format!("{instruction}\n")).collect()));
+            }
+        }
+        (module, assembler, after_passes)
+    }
+}
+
+/// The passes chosen with `--passes=`, `--disable-pass=` and `--print-after=`, on top of the optimisation level
+#[derive(Debug, Clone, Default)]
+pub struct PassOptions {
+    /// Replaces the pipeline of the optimisation level
+    passes: Option<Vec<Pass>>,
+    disabled: HashSet<Pass>,
+    print_after: Vec<Pass>,
+}
+
+impl PassOptions {
+    pub fn new() -> PassOptions {
+        PassOptions::default()
+    }
+    /// Runs exactly the passes of a comma-separated list, which may be empty
+    pub fn set_passes(&mut self, list: &str) -> Result<(), String> {
+        let passes = list.split(',').filter(|name| !name.is_empty()).map(Pass::from_name).collect::<Result<Vec<Pass>, String>>()?;
+        self.passes = Some(passes);
+        Ok(())
+    }
+    pub fn disable(&mut self, name: &str) -> Result<(), String> {
+        self.disabled.insert(Pass::from_name(name)?);
+        Ok(())
+    }
+    /// Asks for the IR or the pseudo-assembly as it is after `name`, see `CompiledProgram::after_passes`
+    pub fn print_after(&mut self, name: &str) -> Result<(), String> {
+        let pass = Pass::from_name(name)?;
+        if !self.print_after.contains(&pass) {
+            self.print_after.push(pass);
+        }
+        Ok(())
+    }
+    pub fn printed_after(&self) -> &[Pass] {
+        &self.print_after
+    }
+    pub fn pipeline(&self, opt_level: OptLevel) -> Pipeline {
+        let passes = match &self.passes {
+            Some(passes) => Pipeline::new(passes).passes,
+            None => Pipeline::for_level(opt_level).passes,
+        };
+        Pipeline { passes: passes.into_iter().filter(|pass| !self.disabled.contains(pass)).collect() }
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashSet;
+
+use crate::assembler::{Instruction, Register};
+
+/// A rewrite of the instructions at the start of a window: how many are replaced and by what
+type Rewrite = (usize, Vec<Instruction>);
+
+/// A pattern over the pseudo-assembly and what replaces it
+pub struct Rule {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Sees the instructions from a point to the next label or return from a call. The rewrite may look at that last instruction
+    /// but not replace it.
+    rewrite: fn(&[Instruction]) -> Option<Rewrite>,
+}
+
+/// Every rule, tried in this order at every instruction
+pub const RULES: [Rule; 5] = [
+    Rule { name: "put-get", description: "`PUT r; GET r` leaves `r` in `A` already", rewrite: put_get },
+    Rule { name: "get-put", description: "`GET r; PUT r` leaves `A` in `r` already", rewrite: get_put },
+    Rule {
+        name: "store-load",
+        description: "a `LOAD` right after a `STORE` to the same address gets back what is in `A`",
+        rewrite: store_load,
+    },
+    Rule {
+        name: "known-constant",
+        description: "a constant built again while a register still holds it is taken from the register",
+        rewrite: known_constant,
+    },
+    Rule { name: "jump-next", description: "a jump to the next instruction does nothing", rewrite: jump_next },
+];
+
+/// The rules switched off with `--disable-peephole=<rule>`
+#[derive(Debug, Clone, Default)]
+pub struct PeepholeOptions {
+    disabled: HashSet<&'static str>,
+}
+
+impl PeepholeOptions {
+    pub fn new() -> PeepholeOptions {
+        PeepholeOptions::default()
+    }
+    /// Switches a rule off, returns an error message for unknown rules
+    pub fn disable(&mut self, name: &str) -> Result<(), String> {
+        let rule = RULES.iter().find(|rule| rule.name == name).ok_or_else(|| {
+            let known: Vec<&str> = RULES.iter().map(|rule| rule.name).collect();
+            format!("unknown peephole rule `{name}`, expected one of: {}", known.join(", "))
+        })?;
+        self.disabled.insert(rule.name);
+        Ok(())
+    }
+    pub fn is_enabled(&self, rule: &Rule) -> bool {
+        !self.disabled.contains(rule.name)
+    }
+}
+
+/// Where the window starting at `index` ends: after the next label, or where a call returns two instructions after its `STRK`
+fn window_end(code: &[Instruction], index: usize) -> usize {
+    for end in index + 1..code.len() {
+        if let Instruction::Label(_) = code[end] {
+            return end + 1;
+        }
+        if let Instruction::Strk(_) = code[end - 1] {
+            return end + 1;
+        }
+    }
+    code.len()
+}
+
+/// One pass over `code`, `None` if no rule matched. The pass manager repeats it until none does.
+/// Jumps go to labels, so they keep their targets.
+pub fn optimise_once(code: &[Instruction], options: &PeepholeOptions) -> Option<Vec<Instruction>> {
+    let mut optimised: Vec<Instruction> = Vec::new();
+    let mut changed = false;
+    let mut index = 0;
+    while index < code.len() {
+        let window = &code[index..window_end(code, index)];
+        match RULES.iter().filter(|rule| options.is_enabled(rule)).find_map(|rule| (rule.rewrite)(window)) {
+            Some((replaced, replacement)) => {
+                optimised.extend(replacement);
+                index += replaced;
+                changed = true;
+            },
+            None => {
+                optimised.push(code[index]);
+                index += 1;
+            },
+        }
+    }
+    changed.then_some(optimised)
+}
+
+fn put_get(window: &[Instruction]) -> Option<Rewrite> {
+    match window {
+        [Instruction::Put(put), Instruction::Get(get), ..] if put == get => Some((2, vec![window[0]])),
+        _ => None,
+    }
+}
+
+fn get_put(window: &[Instruction]) -> Option<Rewrite> {
+    match window {
+        [Instruction::Get(get), Instruction::Put(put), ..] if put == get => Some((2, vec![window[0]])),
+        _ => None,
+    }
+}
+
+fn store_load(window: &[Instruction]) -> Option<Rewrite> {
+    match window {
+        [Instruction::Store(store), Instruction::Load(load), ..] if store == load => Some((2, vec![window[0]])),
+        [Instruction::Store(store), Instruction::Get(get), Instruction::Load(Register::A), ..] if store == get => {
+            Some((3, vec![window[0]]))
+        },
+        _ => None,
+    }
+}
+
+/// `RST a` and the `INC a` and `SHL a` after it, which build a constant
+fn constant_length(code: &[Instruction]) -> usize {
+    match code.first() {
+        Some(Instruction::Rst(Register::A)) => {
+            1 + code[1..].iter().take_while(|instruction| matches!(instruction, Instruction::Inc(Register::A) | Instruction::Shl(Register::A))).count()
+        },
+        _ => 0,
+    }
+}
+
+/// Whether `instruction` may change `register`. Jumps and macro instructions count as changing everything.
+fn writes(instruction: &Instruction, register: Register) -> bool {
+    match instruction {
+        Instruction::Put(written)
+        | Instruction::Rst(written)
+        | Instruction::Inc(written)
+        | Instruction::Dec(written)
+        | Instruction::Shl(written)
+        | Instruction::Shr(written) => *written == register,
+        Instruction::Get(_) | Instruction::Load(_) | Instruction::Add(_) | Instruction::Sub(_) | Instruction::Read => register == Register::A,
+        Instruction::Store(_) | Instruction::Write => false,
+        _ => true,
+    }
+}
+
+/// A constant put into a register and built again later: `GET` it, or keep it if `A` still holds it
+fn known_constant(window: &[Instruction]) -> Option<Rewrite> {
+    let length = constant_length(window);
+    let Some(Instruction::Put(register)) = window.get(length) else {
+        return None;
+    };
+    if length == 0 || *register == Register::A {
+        return None;
+    }
+    let constant = &window[..length];
+    let mut a_holds_constant = true;
+    for index in length + 1..window.len() {
+        if constant_length(&window[index..]) == length && window[index..index + length] == *constant {
+            let mut replacement = window[..index].to_vec();
+            if !a_holds_constant {
+                if length == 1 {
+                    return None;
+                }
+                replacement.push(Instruction::Get(*register));
+            }
+            return Some((index + length, replacement));
+        }
+        if writes(&window[index], *register) {
+            return None;
+        }
+        a_holds_constant &= !writes(&window[index], Register::A);
+    }
+    None
+}
+
+fn jump_next(window: &[Instruction]) -> Option<Rewrite> {
+    match window {
+        [Instruction::Jump(target) | Instruction::Jpos(target) | Instruction::Jzero(target), Instruction::Label(label), ..] if target == label => {
+            Some((1, Vec::new()))
+        },
+        _ => None,
+    }
+}
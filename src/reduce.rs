@@ -0,0 +1,162 @@
+use std::collections::{HashMap, HashSet};
+
+use num_traits::{One, Zero};
+
+use crate::ast::Num;
+use crate::ir::*;
+
+/// Scalars known to hold a constant at some point of a block
+type Known = HashMap<String, Num>;
+
+/// Propagates and folds constants, drops operations that do nothing and rewrites multiplying and dividing by a power of two into shifts.
+/// A block knows the constants its only predecessor ended with, as long as that one comes before it. Returns whether anything changed.
+pub(crate) fn reduce(function: &mut Function) -> bool {
+    let predecessors = function.predecessors();
+    let parameters: HashSet<String> = function.parameters.iter().map(|parameter| parameter.name.clone()).collect();
+    let mut exits: Vec<Known> = Vec::new();
+    let mut changed = false;
+    let mut folded_branch = false;
+    for (index, block) in function.blocks.iter_mut().enumerate() {
+        let mut known = match predecessors[index].as_slice() {
+            [predecessor] if predecessor.0 < index => exits[predecessor.0].clone(),
+            _ => Known::new(),
+        };
+        changed |= reduce_block(block, &mut known, &parameters);
+        if let Terminator::Branch(relation, Operand::Const(num_0), Operand::Const(num_1), yes, no) = &block.terminator {
+            block.terminator = Terminator::Jump(if relation.holds(num_0, num_1) { *yes } else { *no });
+            folded_branch = true;
+        }
+        changed |= remove_unused(block);
+        exits.push(known);
+    }
+    if folded_branch {
+        function.renumber();
+    }
+    changed || folded_branch
+}
+
+fn reduce_block(block: &mut Block, known: &mut Known, parameters: &HashSet<String>) -> bool {
+    let mut changed = false;
+    // Temporaries replaced by what they turned out to be
+    let mut replacements: HashMap<Temp, Operand> = HashMap::new();
+    for operation in &mut block.operations {
+        for operand in operation.operands_mut() {
+            changed |= substitute(operand, &replacements);
+        }
+        match operation {
+            Operation::Load(temp, Place::Variable(name)) => {
+                if let Some(num) = known.get(name) {
+                    replacements.insert(*temp, Operand::Const(num.clone()));
+                }
+            },
+            Operation::Binary(temp, op, operand_0, operand_1) => match simplify(*op, operand_0, operand_1) {
+                Some(operand) => {
+                    replacements.insert(*temp, operand);
+                },
+                None => {
+                    if let Some((shift, operand, count)) = shift_of(*op, operand_0, operand_1) {
+                        *operation = Operation::Binary(*temp, shift, operand, Operand::Const(Num::from(count)));
+                        changed = true;
+                    }
+                },
+            },
+            Operation::Store(Place::Variable(name), operand) => {
+                kill(known, name, parameters);
+                if let Operand::Const(num) = operand {
+                    known.insert(name.clone(), num.clone());
+                }
+            },
+            Operation::Read(Place::Variable(name)) => kill(known, name, parameters),
+            // The callee may assign any of its arguments
+            Operation::Call(_, _) => known.clear(),
+            Operation::Load(_, Place::Element(_, _)) | Operation::Store(Place::Element(_, _), _) | Operation::Read(Place::Element(_, _)) => {},
+            Operation::Write(_) => {},
+        }
+    }
+    for operand in block.terminator.operands_mut() {
+        changed |= substitute(operand, &replacements);
+    }
+    changed
+}
+
+/// Replaces a temporary that turned out to be something else, returns whether it did
+fn substitute(operand: &mut Operand, replacements: &HashMap<Temp, Operand>) -> bool {
+    match operand_temp(operand).and_then(|temp| replacements.get(&temp)) {
+        Some(replacement) => {
+            *operand = replacement.clone();
+            true
+        },
+        None => false,
+    }
+}
+
+/// What `operand_0 op operand_1` is without computing it, when one of the operands is a constant that makes it trivial
+fn simplify(op: BinaryOp, operand_0: &Operand, operand_1: &Operand) -> Option<Operand> {
+    let is = |operand: &Operand, expected: u8| matches!(operand, Operand::Const(num) if *num == Num::from(expected));
+    let zero = Operand::Const(Num::zero());
+    match (op, operand_0, operand_1) {
+        (_, Operand::Const(num_0), Operand::Const(num_1)) => Some(Operand::Const(op.fold(num_0, num_1))),
+        (BinaryOp::Add, _, _) if is(operand_0, 0) => Some(operand_1.clone()),
+        (BinaryOp::Add | BinaryOp::Sub | BinaryOp::Shl | BinaryOp::Shr, _, _) if is(operand_1, 0) => Some(operand_0.clone()),
+        (BinaryOp::Mul | BinaryOp::Div, _, _) if is(operand_1, 1) => Some(operand_0.clone()),
+        (BinaryOp::Mul, _, _) if is(operand_0, 1) => Some(operand_1.clone()),
+        // Subtraction stops at zero, dividing by zero gives zero and every remainder modulo 1 is zero
+        (_, _, _) if is(operand_0, 0) => Some(zero),
+        (BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod, _, _) if is(operand_1, 0) => Some(zero),
+        (BinaryOp::Mod, _, _) if is(operand_1, 1) => Some(zero),
+        _ => None,
+    }
+}
+
+/// The shift multiplying or dividing by a power of two is, with the operand shifted and the number of bits
+fn shift_of(op: BinaryOp, operand_0: &Operand, operand_1: &Operand) -> Option<(BinaryOp, Operand, u64)> {
+    let exponent = |operand: &Operand| match operand {
+        Operand::Const(num) if num.count_ones() == 1 && !num.is_one() => Some(num.bits() - 1),
+        _ => None,
+    };
+    match op {
+        BinaryOp::Mul => match (exponent(operand_0), exponent(operand_1)) {
+            (_, Some(count)) => Some((BinaryOp::Shl, operand_0.clone(), count)),
+            (Some(count), _) => Some((BinaryOp::Shl, operand_1.clone(), count)),
+            (None, None) => None,
+        },
+        BinaryOp::Div => exponent(operand_1).map(|count| (BinaryOp::Shr, operand_0.clone(), count)),
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mod | BinaryOp::Shl | BinaryOp::Shr => None,
+    }
+}
+
+/// Forgets what `name` holds. Parameters may all be the same variable, so assigning one forgets them all.
+fn kill(known: &mut Known, name: &str, parameters: &HashSet<String>) {
+    match parameters.contains(name) {
+        true => known.retain(|variable, _| !parameters.contains(variable)),
+        false => {
+            known.remove(name);
+        },
+    }
+}
+
+/// Drops the loads and computations whose temporaries are never used, going backwards so that their operands follow
+fn remove_unused(block: &mut Block) -> bool {
+    let mut used: HashSet<Temp> = block.terminator.operands_mut().into_iter().filter_map(|operand| operand_temp(operand)).collect();
+    let mut kept = vec![true; block.operations.len()];
+    for (index, operation) in block.operations.iter_mut().enumerate().rev() {
+        if operation.defined().is_some_and(|temp| !used.contains(&temp)) {
+            kept[index] = false;
+            continue;
+        }
+        used.extend(operation.operands_mut().into_iter().filter_map(|operand| operand_temp(operand)));
+    }
+    if kept.iter().all(|kept| *kept) {
+        return false;
+    }
+    let operations = std::mem::take(&mut block.operations).into_iter().zip(std::mem::take(&mut block.origins));
+    (block.operations, block.origins) = operations.zip(kept).filter(|(_, kept)| *kept).map(|(pair, _)| pair).unzip();
+    true
+}
+
+fn operand_temp(operand: &Operand) -> Option<Temp> {
+    match operand {
+        Operand::Temp(temp) => Some(*temp),
+        Operand::Const(_) => None,
+    }
+}
@@ -0,0 +1,203 @@
+use std::fmt::Display;
+
+use crate::ast::*;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SymbolKind {
+    Scalar,
+    /// With its declared size
+    Array(Num),
+    Parameter,
+    /// A `T` parameter, its size comes from the caller
+    ArrayParameter,
+    /// With its parameters as written in the head, `T` included
+    Procedure(Vec<String>),
+}
+
+/// A declared name with every place that refers to it
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// The name in its declaration
+    pub span: Span,
+    /// The procedure or main declaring it, a procedure's own span for procedures
+    pub scope: Span,
+    /// Uses of the name, the declaration not included
+    pub references: Vec<Span>,
+}
+
+impl Display for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            SymbolKind::Scalar => write!(f, "scalar `{}`", self.name),
+            SymbolKind::Array(size) => write!(f, "array `{}[{size}]`", self.name),
+            SymbolKind::Parameter => write!(f, "parameter `{}`", self.name),
+            SymbolKind::ArrayParameter => write!(f, "`T` parameter `{}`, an array", self.name),
+            SymbolKind::Procedure(parameters) => write!(f, "procedure `{}({})`", self.name, parameters.join(", ")),
+        }
+    }
+}
+
+/// Every declaration of a program with the names resolved to them, for editor support.
+/// Undeclared names refer to nothing, duplicate declarations get the uses of the first one.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolIndex {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolIndex {
+    pub fn new(program: &Program) -> SymbolIndex {
+        let mut index = SymbolIndex::default();
+        for ((name, parameters, _), declarations, commands, span) in program.0.iter().flatten() {
+            let parameter_names = parameters
+                .iter()
+                .map(|parameter| match parameter {
+                    ArgumentsDeclarationVariant::Base(id) => id.0.clone(),
+                    ArgumentsDeclarationVariant::Table(id, _) => format!("T {}", id.0),
+                })
+                .collect();
+            index.declare(name, SymbolKind::Procedure(parameter_names), *span);
+            for parameter in parameters {
+                match parameter {
+                    ArgumentsDeclarationVariant::Base(id) => index.declare(id, SymbolKind::Parameter, *span),
+                    ArgumentsDeclarationVariant::Table(id, _) => index.declare(id, SymbolKind::ArrayParameter, *span),
+                }
+            }
+            index.declare_all(declarations, *span);
+            index.resolve_commands(commands, *span);
+        }
+        let (declarations, commands, span) = &program.1;
+        index.declare_all(declarations, *span);
+        index.resolve_commands(commands, *span);
+        index
+    }
+    fn declare(&mut self, id: &Pidentifier, kind: SymbolKind, scope: Span) {
+        self.symbols.push(Symbol { name: id.0.clone(), kind, span: id.1, scope, references: Vec::new() });
+    }
+    fn declare_all(&mut self, declarations: &Option<Declarations>, scope: Span) {
+        for declaration in declarations.iter().flatten() {
+            match declaration {
+                DeclarationVariant::Base(id) => self.declare(id, SymbolKind::Scalar, scope),
+                DeclarationVariant::NumIndexed(id, size, _) => self.declare(id, SymbolKind::Array(size.clone()), scope),
+            }
+        }
+    }
+    /// Records a use of a variable in `scope`
+    fn resolve_variable(&mut self, id: &Pidentifier, scope: Span) {
+        let symbol = self
+            .symbols
+            .iter_mut()
+            .find(|symbol| symbol.scope == scope && symbol.name == id.0 && !matches!(symbol.kind, SymbolKind::Procedure(_)));
+        if let Some(symbol) = symbol {
+            symbol.references.push(id.1);
+        }
+    }
+    fn resolve_identifier(&mut self, identifier: &Identifier, scope: Span) {
+        match identifier {
+            Identifier::Base(id) | Identifier::NumIndexed(id, _, _) => self.resolve_variable(id, scope),
+            Identifier::PidIndexed(id, index, _) => {
+                self.resolve_variable(id, scope);
+                self.resolve_variable(index, scope);
+            },
+        }
+    }
+    fn resolve_value(&mut self, value: &Value, scope: Span) {
+        if let Value::Id(identifier) = value {
+            self.resolve_identifier(identifier, scope);
+        }
+    }
+    fn resolve_condition(&mut self, condition: &Condition, scope: Span) {
+        match condition {
+            Condition::Equal(value_0, value_1, _)
+            | Condition::NotEqual(value_0, value_1, _)
+            | Condition::Greater(value_0, value_1, _)
+            | Condition::Lower(value_0, value_1, _)
+            | Condition::GreaterOrEqual(value_0, value_1, _)
+            | Condition::LowerOrEqual(value_0, value_1, _) => {
+                self.resolve_value(value_0, scope);
+                self.resolve_value(value_1, scope);
+            },
+        }
+    }
+    fn resolve_commands(&mut self, commands: &Commands, scope: Span) {
+        for command in commands {
+            match command {
+                Command::Assign(identifier, expression, _) => {
+                    self.resolve_identifier(identifier, scope);
+                    match expression {
+                        Expression::Val(value, _) => self.resolve_value(value, scope),
+                        Expression::Add(value_0, value_1, _)
+                        | Expression::Substract(value_0, value_1, _)
+                        | Expression::Multiply(value_0, value_1, _)
+                        | Expression::Divide(value_0, value_1, _)
+                        | Expression::Modulo(value_0, value_1, _) => {
+                            self.resolve_value(value_0, scope);
+                            self.resolve_value(value_1, scope);
+                        },
+                    }
+                },
+                Command::If(condition, commands, else_commands, _) => {
+                    self.resolve_condition(condition, scope);
+                    self.resolve_commands(commands, scope);
+                    if let Some(else_commands) = else_commands {
+                        self.resolve_commands(else_commands, scope);
+                    }
+                },
+                Command::While(condition, commands, _) => {
+                    self.resolve_condition(condition, scope);
+                    self.resolve_commands(commands, scope);
+                },
+                Command::Repeat(commands, condition, _) => {
+                    self.resolve_commands(commands, scope);
+                    self.resolve_condition(condition, scope);
+                },
+                Command::ProcCall((procedure_id, arguments), _) => {
+                    let procedure = self
+                        .symbols
+                        .iter_mut()
+                        .find(|symbol| symbol.name == procedure_id.0 && matches!(symbol.kind, SymbolKind::Procedure(_)));
+                    if let Some(procedure) = procedure {
+                        procedure.references.push(procedure_id.1);
+                    }
+                    for argument in arguments {
+                        self.resolve_variable(argument, scope);
+                    }
+                },
+                Command::Read(identifier, _) => self.resolve_identifier(identifier, scope),
+                Command::Write(value, _) => self.resolve_value(value, scope),
+            }
+        }
+    }
+    /// Every declaration, procedures first in source order, then the names inside them
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+    pub fn procedures(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter().filter(|symbol| matches!(symbol.kind, SymbolKind::Procedure(_)))
+    }
+    /// The symbol declared or used at byte `offset`, the end of a name counts as on it
+    pub fn symbol_at(&self, offset: usize) -> Option<&Symbol> {
+        let on = |span: &Span| span.start <= offset && offset <= span.end;
+        self.symbols.iter().find(|symbol| on(&symbol.span) || symbol.references.iter().any(on))
+    }
+    /// Names usable at byte `offset`: the variables of the procedure or main around it and the procedures it may call
+    pub fn visible_at(&self, offset: usize) -> Vec<&Symbol> {
+        let Some(scope) = self
+            .symbols
+            .iter()
+            .map(|symbol| symbol.scope)
+            .find(|scope| scope.start <= offset && offset <= scope.end)
+        else {
+            return Vec::new();
+        };
+        // Procedures may only call the ones declared before them, main may call all of them
+        self.symbols
+            .iter()
+            .filter(|symbol| match symbol.kind {
+                SymbolKind::Procedure(_) => symbol.scope.end < scope.start,
+                _ => symbol.scope == scope,
+            })
+            .collect()
+    }
+}
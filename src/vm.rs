@@ -0,0 +1,205 @@
+//! Interpreter of the target machine's code, following `maszyna_wirtualna/mw.cc` with unbounded registers
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::{BufRead, Write};
+
+use num_bigint::BigUint;
+use num_traits::{ToPrimitive, Zero};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operation {
+    Read,
+    Write,
+    Load(usize),
+    Store(usize),
+    Add(usize),
+    Sub(usize),
+    Get(usize),
+    Put(usize),
+    Rst(usize),
+    Inc(usize),
+    Dec(usize),
+    Shl(usize),
+    Shr(usize),
+    Jump(usize),
+    Jpos(usize),
+    Jzero(usize),
+    Strk(usize),
+    Jumpr(usize),
+    Halt,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    /// 1-based line of the code and its text
+    InvalidInstruction(usize, String),
+    /// The program counter left the program
+    JumpOutOfRange(BigUint),
+    InvalidInput(String),
+    EndOfInput,
+    Io(String),
+}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmError::InvalidInstruction(line, text) => write!(f, "invalid instruction `{text}` in line {line}"),
+            VmError::JumpOutOfRange(target) => write!(f, "jump to nonexistent instruction {target}"),
+            VmError::InvalidInput(text) => write!(f, "`{text}` is not a natural number"),
+            VmError::EndOfInput => write!(f, "READ reached the end of the input"),
+            VmError::Io(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+/// What a finished run cost, `cost` includes `io_cost`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Execution {
+    pub cost: u64,
+    pub io_cost: u64,
+}
+
+/// Runs `code`, taking the numbers for `READ` from `input` and printing every `WRITE` as a line of `output`
+pub fn run(code: &str, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<Execution, VmError> {
+    let program = parse(code)?;
+    let mut registers: [BigUint; 8] = Default::default();
+    let mut memory: HashMap<BigUint, BigUint> = HashMap::new();
+    let mut tokens: Vec<String> = Vec::new();
+    let mut execution = Execution::default();
+    let mut pc: usize = 0;
+    loop {
+        let operation = *program.get(pc).ok_or_else(|| VmError::JumpOutOfRange(BigUint::from(pc)))?;
+        let (cost, next) = match operation {
+            Operation::Read => {
+                registers[0] = read_number(input, &mut tokens)?;
+                execution.io_cost += 100;
+                (100, pc + 1)
+            },
+            Operation::Write => {
+                writeln!(output, "{}", registers[0]).map_err(|error| VmError::Io(error.to_string()))?;
+                execution.io_cost += 100;
+                (100, pc + 1)
+            },
+            Operation::Load(register) => {
+                registers[0] = memory.get(&registers[register]).cloned().unwrap_or_default();
+                (50, pc + 1)
+            },
+            Operation::Store(register) => {
+                memory.insert(registers[register].clone(), registers[0].clone());
+                (50, pc + 1)
+            },
+            Operation::Add(register) => {
+                registers[0] = &registers[0] + &registers[register];
+                (5, pc + 1)
+            },
+            Operation::Sub(register) => {
+                registers[0] = if registers[0] >= registers[register] { &registers[0] - &registers[register] } else { BigUint::zero() };
+                (5, pc + 1)
+            },
+            Operation::Get(register) => {
+                registers[0] = registers[register].clone();
+                (1, pc + 1)
+            },
+            Operation::Put(register) => {
+                registers[register] = registers[0].clone();
+                (1, pc + 1)
+            },
+            Operation::Rst(register) => {
+                registers[register] = BigUint::zero();
+                (1, pc + 1)
+            },
+            Operation::Inc(register) => {
+                registers[register] += 1u8;
+                (1, pc + 1)
+            },
+            Operation::Dec(register) => {
+                if !registers[register].is_zero() {
+                    registers[register] -= 1u8;
+                }
+                (1, pc + 1)
+            },
+            Operation::Shl(register) => {
+                registers[register] <<= 1;
+                (1, pc + 1)
+            },
+            Operation::Shr(register) => {
+                registers[register] >>= 1;
+                (1, pc + 1)
+            },
+            Operation::Jump(target) => (1, target),
+            Operation::Jpos(target) => (1, if registers[0].is_zero() { pc + 1 } else { target }),
+            Operation::Jzero(target) => (1, if registers[0].is_zero() { target } else { pc + 1 }),
+            Operation::Strk(register) => {
+                registers[register] = BigUint::from(pc);
+                (1, pc + 1)
+            },
+            Operation::Jumpr(register) => {
+                let target = registers[register].to_usize().ok_or_else(|| VmError::JumpOutOfRange(registers[register].clone()))?;
+                (1, target)
+            },
+            Operation::Halt => return Ok(execution),
+        };
+        execution.cost += cost;
+        pc = next;
+    }
+}
+
+fn read_number(input: &mut dyn BufRead, tokens: &mut Vec<String>) -> Result<BigUint, VmError> {
+    while tokens.is_empty() {
+        let mut line = String::new();
+        let read = input.read_line(&mut line).map_err(|error| VmError::Io(error.to_string()))?;
+        if read == 0 {
+            return Err(VmError::EndOfInput);
+        }
+        tokens.extend(line.split_whitespace().rev().map(str::to_string));
+    }
+    let token = tokens.pop().unwrap_or_default();
+    token.parse().map_err(|_| VmError::InvalidInput(token))
+}
+
+fn parse(code: &str) -> Result<Vec<Operation>, VmError> {
+    let mut program = Vec::new();
+    for (index, line) in code.lines().enumerate() {
+        let text = line.split('#').next().unwrap_or_default().trim();
+        if text.is_empty() {
+            continue;
+        }
+        let invalid = || VmError::InvalidInstruction(index + 1, line.to_string());
+        let mut words = text.split_whitespace();
+        let mnemonic = words.next().unwrap_or_default();
+        let argument = words.next();
+        if words.next().is_some() {
+            return Err(invalid());
+        }
+        let register = || match argument.map(str::as_bytes) {
+            Some(&[name @ b'a'..=b'h']) => Ok((name - b'a') as usize),
+            _ => Err(invalid()),
+        };
+        let target = || argument.and_then(|target| target.parse::<usize>().ok()).ok_or_else(invalid);
+        let operation = match mnemonic {
+            "READ" if argument.is_none() => Operation::Read,
+            "WRITE" if argument.is_none() => Operation::Write,
+            "HALT" if argument.is_none() => Operation::Halt,
+            "LOAD" => Operation::Load(register()?),
+            "STORE" => Operation::Store(register()?),
+            "ADD" => Operation::Add(register()?),
+            "SUB" => Operation::Sub(register()?),
+            "GET" => Operation::Get(register()?),
+            "PUT" => Operation::Put(register()?),
+            "RST" => Operation::Rst(register()?),
+            "INC" => Operation::Inc(register()?),
+            "DEC" => Operation::Dec(register()?),
+            "SHL" => Operation::Shl(register()?),
+            "SHR" => Operation::Shr(register()?),
+            "STRK" => Operation::Strk(register()?),
+            "JUMPR" => Operation::Jumpr(register()?),
+            "JUMP" => Operation::Jump(target()?),
+            "JPOS" => Operation::Jpos(target()?),
+            "JZERO" => Operation::Jzero(target()?),
+            _ => return Err(invalid()),
+        };
+        program.push(operation);
+    }
+    Ok(program)
+}
@@ -0,0 +1,51 @@
+use std::fs;
+
+use gembalang::format::format;
+
+#[test]
+fn formats_every_example_idempotently() {
+    for directory in ["errors", "gembala", "my", "slowik"] {
+        for entry in fs::read_dir(format!("{}/examples/{directory}", env!("CARGO_MANIFEST_DIR"))).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|extension| extension == "imp") {
+                let source = fs::read_to_string(&path).unwrap();
+                let formatted = format(&source).unwrap_or_else(|error| panic!("{} failed: {}", path.display(), error.message));
+                assert_eq!(format(&formatted).unwrap(), formatted, "formatting {} twice changes it", path.display());
+            }
+        }
+    }
+}
+
+#[test]
+fn keeps_comments_in_place() {
+    let source = "# header\nPROCEDURE p(T t,a) IS IN\n  # before\n  t[a]:=a+1; # trailing\nEND\nPROGRAM IS x[2],y IN READ y;\n\n\n  p(x,y); # call\nIF y>0 THEN WRITE x[0];\nELSE # no\n WRITE 0; ENDIF\nEND\n";
+    assert_eq!(
+        format(source).unwrap(),
+        concat!(
+            "# header\n",
+            "PROCEDURE p(T t, a) IS\n",
+            "IN\n",
+            "  # before\n",
+            "  t[a] := a + 1; # trailing\n",
+            "END\n",
+            "\n",
+            "PROGRAM IS\n",
+            "  x[2], y\n",
+            "IN\n",
+            "  READ y;\n",
+            "\n",
+            "  p(x, y); # call\n",
+            "  IF y > 0 THEN\n",
+            "    WRITE x[0];\n",
+            "  ELSE # no\n",
+            "    WRITE 0;\n",
+            "  ENDIF\n",
+            "END\n",
+        )
+    );
+}
+
+#[test]
+fn reports_syntax_errors() {
+    assert_eq!(format("PROGRAM IS IN a := ; END").unwrap_err().code, "E0003");
+}
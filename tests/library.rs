@@ -0,0 +1,148 @@
+use gembalang::diagnostics::WarningOptions;
+use gembalang::assembler::Instruction;
+use gembalang::passes::{Pass, PassOptions, Pipeline};
+use gembalang::{compile, CompileOptions, OptLevel};
+
+#[test]
+fn compiles_to_assembly() {
+    let program = compile("PROGRAM IS a IN READ a; WRITE a; END", &CompileOptions::default()).unwrap();
+    assert!(program.assembly.ends_with("HALT\n"));
+    assert!(!program.pseudo_assembly.is_empty());
+    assert!(program.warnings.is_empty());
+}
+
+#[test]
+fn reports_syntax_errors() {
+    let diagnostics = compile("PROGRAM IS a IN a := ; END", &CompileOptions::default()).unwrap_err();
+    let codes: Vec<&str> = diagnostics.iter().map(|diagnostic| diagnostic.code).collect();
+    assert_eq!(codes, ["E0003"]);
+}
+
+#[test]
+fn reports_every_semantic_error() {
+    let diagnostics = compile("PROGRAM IS a IN b := 1; c := 2; END", &CompileOptions::default()).unwrap_err();
+    let codes: Vec<&str> = diagnostics.errors().map(|diagnostic| diagnostic.code).collect();
+    assert_eq!(codes, ["E0101", "E0101"]);
+}
+
+#[test]
+fn denied_warnings_fail() {
+    let source = "PROGRAM IS a, b IN WRITE a; END";
+    let program = compile(source, &CompileOptions::default()).unwrap();
+    assert_eq!(program.warnings.len(), 2);
+    let mut warnings = WarningOptions::new();
+    warnings.apply_flag("-Werror").unwrap();
+    assert!(compile(source, &CompileOptions { warnings, ..Default::default() }).is_err());
+}
+
+#[test]
+fn warns_about_reads_by_path() {
+    let cases = [
+        ("PROGRAM IS a, b IN READ a; IF a > 0 THEN b := 1; ENDIF WRITE b; END", vec!["W0004"]),
+        ("PROGRAM IS a, b IN READ a; IF a > 0 THEN b := 1; ELSE b := 2; ENDIF WRITE b; END", vec![]),
+        ("PROGRAM IS a, b IN READ a; WHILE a > 0 DO IF a = 1 THEN WRITE b; ENDIF b := a; a := a - 1; ENDWHILE END", vec!["W0004"]),
+        ("PROGRAM IS a, b IN READ a; REPEAT a := a - 1; UNTIL b = a; END", vec!["W0001"]),
+        ("PROCEDURE p(x) IS IN x := 1; END PROGRAM IS a IN p(a); WRITE a; END", vec![]),
+        ("PROCEDURE p(x, y) IS IN IF y > 0 THEN x := 1; ENDIF END PROGRAM IS a, b IN READ b; p(a, b); WRITE a; END", vec!["W0004"]),
+    ];
+    for (source, expected) in cases {
+        let program = compile(source, &CompileOptions::default()).unwrap();
+        let codes: Vec<&str> = program.warnings.iter().map(|diagnostic| diagnostic.code).collect();
+        assert_eq!(codes, expected, "{source}");
+    }
+}
+
+#[test]
+fn dumps_the_ast() {
+    let ast = gembalang::parse("PROGRAM IS a IN READ a; END").unwrap();
+    assert_eq!(
+        gembalang::dump::ast_json(&ast),
+        concat!(
+            r#"{"kind":"program","procedures":[],"main":{"kind":"main","declarations":[{"kind":"scalar","name":"a","span":{"start":11,"end":12}}],"#,
+            r#""commands":[{"kind":"read","target":{"kind":"variable","name":"a","span":{"start":21,"end":22}},"span":{"start":16,"end":23}}],"#,
+            r#""span":{"start":0,"end":27}}}"#,
+            "\n"
+        )
+    );
+    assert_eq!(
+        gembalang::dump::ast_tree(&ast),
+        "program\n  procedures: [0]\n  main: main @0..27\n    declarations: [1]\n      scalar name=a @11..12\n    commands: [1]\n      read @16..23\n        target: variable name=a @21..22\n"
+    );
+}
+
+#[test]
+fn checks_constant_indices_of_array_parameters() {
+    let source = "PROCEDURE p(T t) IS IN t[5] := 1; END PROCEDURE q(T t) IS IN p(t); END PROGRAM IS a[5], b[6] IN q(b); q(a); END";
+    let diagnostics = compile(source, &CompileOptions::default()).unwrap_err();
+    let codes: Vec<&str> = diagnostics.errors().map(|diagnostic| diagnostic.code).collect();
+    assert_eq!(codes, ["E0104"]);
+}
+
+#[test]
+fn procedures_never_active_together_share_memory() {
+    let procedures = "PROCEDURE p(x) IS t[4611686018427387904] IN t[0] := x; END
+        PROCEDURE q(x) IS t[4611686018427387904] IN t[0] := x; END
+        PROCEDURE r(x) IS t[4611686018427387904] IN t[0] := x; END";
+    let siblings = format!("{procedures} PROGRAM IS a[4611686018427387904], n IN n := 1; p(n); q(n); r(n); END");
+    assert!(compile(&siblings, &CompileOptions::default()).is_ok());
+    let nested = siblings.replace("PROCEDURE q(x) IS t[4611686018427387904] IN t[0] := x;", "PROCEDURE q(x) IS t[4611686018427387904] IN p(x);");
+    let nested = nested.replace("PROCEDURE r(x) IS t[4611686018427387904] IN t[0] := x;", "PROCEDURE r(x) IS t[4611686018427387904] IN q(x);");
+    assert!(compile(&nested, &CompileOptions::default()).is_err());
+}
+
+#[test]
+fn reports_static_costs() {
+    let source = "PROGRAM IS n, s IN\n  READ n; s := 0;\n  WHILE n > 0 DO\n    s := s + n; n := n - 1;\n  ENDWHILE\n  WRITE s;\nEND";
+    let program = compile(source, &CompileOptions::default()).unwrap();
+    let main = &program.costs.bodies[0];
+    assert_eq!(main.procedure, None);
+    let loops: Vec<usize> = main.commands.iter().map(|command| command.loops).collect();
+    assert_eq!(loops, [0, 0, 0, 1, 1, 0]);
+    assert!(main.commands[2].cost > main.commands[3].cost + main.commands[4].cost);
+    let total: u64 = program.pseudo_assembly.iter().map(|instruction| instruction.cost()).sum();
+    assert_eq!(program.costs.total, total);
+    let report = program.cost_report(source);
+    assert!(report.contains("      4  "));
+    assert!(report.lines().any(|line| line.contains("*") && line.ends_with("    s := s + n;")));
+    // A procedure never called is not listed as inlined
+    let source = "PROCEDURE unused(x) IS IN x := 1; END PROCEDURE used(x) IS IN x := 2; END PROGRAM IS a IN used(a); WRITE a; END";
+    let program = compile(source, &CompileOptions::default()).unwrap();
+    assert_eq!(program.costs.inlined, ["used"]);
+}
+
+#[test]
+fn lays_labels_out_as_addresses() {
+    let source = "PROGRAM IS n, s IN READ n; s := 1; WHILE n > 0 DO s := s * n; n := n - 1; ENDWHILE WRITE s; END";
+    let options = CompileOptions { opt_level: OptLevel::O0, ..Default::default() };
+    let program = compile(source, &options).unwrap();
+    let labels = program.pseudo_assembly.iter().filter(|instruction| matches!(instruction, Instruction::Label(_))).count();
+    // Only the loop header and the block after the loop are jumped to
+    assert_eq!(labels, 2);
+    let lines: Vec<&str> = program.assembly.lines().collect();
+    for line in &lines {
+        if let Some(target) = line.strip_prefix("JUMP ").or_else(|| line.strip_prefix("JPOS ")).or_else(|| line.strip_prefix("JZERO ")) {
+            assert!(target.parse::<usize>().unwrap() < lines.len(), "`{line}` leaves the program");
+        }
+    }
+}
+
+#[test]
+fn builds_pass_pipelines() {
+    assert_eq!(Pipeline::for_level(OptLevel::O0).passes(), [Pass::Inline]);
+    let mut passes = PassOptions::new();
+    passes.set_passes("peephole,reduce,registers").unwrap();
+    passes.disable("registers").unwrap();
+    assert_eq!(passes.pipeline(OptLevel::O0).passes(), [Pass::Reduce, Pass::Peephole]);
+    assert!(passes.disable("unrolling").is_err());
+    passes.print_after("reduce").unwrap();
+    passes.print_after("peephole").unwrap();
+    let options = CompileOptions { passes, ..Default::default() };
+    let program = compile("PROGRAM IS a, b IN READ a; b := a * 4; WRITE b; END", &options).unwrap();
+    let [(Pass::Reduce, reduced), (Pass::Peephole, optimised)] = &program.after_passes[..] else {
+        panic!("expected the IR after `reduce` and the code after `peephole`");
+    };
+    assert!(reduced.contains(" << 2"));
+    assert_eq!(*reduced, program.ir.to_string());
+    let pseudo_assembly: String = program.pseudo_assembly.iter().map(|instruction| format!("{instruction}\n")).collect();
+    assert_eq!(*optimised, pseudo_assembly);
+}
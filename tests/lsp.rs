@@ -0,0 +1,62 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{ChildStdout, Command, Stdio};
+
+use serde_json::{json, Value};
+
+fn send(stdin: &mut impl Write, message: Value) {
+    let body = message.to_string();
+    write!(stdin, "Content-Length: {}\r\n\r\n{body}", body.len()).unwrap();
+    stdin.flush().unwrap();
+}
+
+fn receive(stdout: &mut BufReader<ChildStdout>) -> Value {
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        stdout.read_line(&mut header).unwrap();
+        match header.trim().strip_prefix("Content-Length: ") {
+            Some(value) => length = value.parse().unwrap(),
+            None if header.trim().is_empty() => break,
+            None => {},
+        }
+    }
+    let mut body = vec![0; length];
+    stdout.read_exact(&mut body).unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[test]
+fn answers_an_editor_session() {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_kompilator-lsp"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = server.stdin.take().unwrap();
+    let mut stdout = BufReader::new(server.stdout.take().unwrap());
+    let uri = "file:///example.imp";
+    let position = |line: u32, character: u32| json!({"textDocument": {"uri": uri}, "position": {"line": line, "character": character}});
+
+    send(&mut stdin, json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {"capabilities": {}}}));
+    assert_eq!(receive(&mut stdout)["result"]["capabilities"]["hoverProvider"], true);
+    send(&mut stdin, json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}));
+    let text = "PROCEDURE p(T t) IS IN t[0] := 1; END\nPROGRAM IS a[3] IN p(a); WRITE q; END\n";
+    send(
+        &mut stdin,
+        json!({"jsonrpc": "2.0", "method": "textDocument/didOpen",
+               "params": {"textDocument": {"uri": uri, "languageId": "gembalang", "version": 1, "text": text}}}),
+    );
+    let published = receive(&mut stdout);
+    assert_eq!(published["params"]["diagnostics"][0]["code"], "E0101");
+    assert_eq!(published["params"]["diagnostics"][0]["range"]["start"], json!({"line": 1, "character": 31}));
+
+    send(&mut stdin, json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/hover", "params": position(1, 21)}));
+    assert_eq!(receive(&mut stdout)["result"]["contents"]["value"], "array `a[3]`");
+    send(&mut stdin, json!({"jsonrpc": "2.0", "id": 3, "method": "textDocument/definition", "params": position(1, 19)}));
+    assert_eq!(receive(&mut stdout)["result"]["range"]["start"], json!({"line": 0, "character": 10}));
+
+    send(&mut stdin, json!({"jsonrpc": "2.0", "id": 4, "method": "shutdown"}));
+    assert_eq!(receive(&mut stdout)["id"], 4);
+    send(&mut stdin, json!({"jsonrpc": "2.0", "method": "exit"}));
+    assert!(server.wait().unwrap().success());
+}
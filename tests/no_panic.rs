@@ -0,0 +1,172 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use serde_json::{json, Value};
+
+/// Rust exits with this code when the main thread panics
+const PANIC_EXIT_CODE: i32 = 101;
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn kompilator(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_kompilator")).args(args).output().unwrap()
+}
+
+fn assert_no_panic(output: &Output, what: &str) {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_ne!(output.status.code(), Some(PANIC_EXIT_CODE), "kompilator panicked on {what}:\n{stderr}");
+    assert!(output.status.code().is_some(), "kompilator was killed on {what}:\n{stderr}");
+    assert!(!stderr.contains("panicked"), "kompilator panicked on {what}:\n{stderr}");
+}
+
+/// Compiles `source` and returns whether it succeeded, failing the test on a panic
+fn compile_source(dir: &Path, source: &str, what: &str) -> bool {
+    let input = dir.join("input.imp");
+    let output = dir.join("output.mr");
+    fs::write(&input, source).unwrap();
+    let result = kompilator(&[input.to_str().unwrap(), output.to_str().unwrap()]);
+    assert_no_panic(&result, what);
+    result.status.success()
+}
+
+fn examples() -> Vec<PathBuf> {
+    let mut examples = Vec::new();
+    for dir in fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/examples")).unwrap() {
+        for file in fs::read_dir(dir.unwrap().path()).unwrap() {
+            let path = file.unwrap().path();
+            if path.extension().is_some_and(|extension| extension == "imp") {
+                examples.push(path);
+            }
+        }
+    }
+    examples.sort();
+    examples
+}
+
+#[test]
+fn wrong_number_of_arguments() {
+    for args in [&[][..], &["a.imp"], &["a.imp", "b.mr", "c"]] {
+        let output = kompilator(args);
+        assert_no_panic(&output, &format!("arguments {args:?}"));
+        assert!(!output.status.success());
+    }
+}
+
+#[test]
+fn missing_input_file() {
+    let output = kompilator(&["/nonexistent/input.imp", "/nonexistent/output.mr"]);
+    assert_no_panic(&output, "a missing input file");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn input_is_not_utf8() {
+    let dir = scratch_dir("input_is_not_utf8");
+    let input = dir.join("input.imp");
+    fs::write(&input, [0xff, 0xfe, 0x00, 0x80]).unwrap();
+    let output = kompilator(&[input.to_str().unwrap(), dir.join("output.mr").to_str().unwrap()]);
+    assert_no_panic(&output, "an input that is not UTF-8");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn unwritable_output_file() {
+    let input = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/gembala/example1.imp");
+    let output = kompilator(&[input, "/nonexistent/output.mr"]);
+    assert_no_panic(&output, "an unwritable output file");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn json_diagnostics() {
+    let dir = scratch_dir("json_diagnostics");
+    let input = dir.join("input.imp");
+    fs::write(&input, "PROGRAM IS a IN\n  q := 1; WRITE a;\nEND\n").unwrap();
+    let input = input.to_str().unwrap();
+    let output = kompilator(&["--error-format=json", input, dir.join("output.mr").to_str().unwrap()]);
+    assert_no_panic(&output, "json diagnostics");
+    let diagnostics: Vec<Value> = output.stderr.split(|byte| *byte == b'\n').filter(|line| !line.is_empty()).map(|line| serde_json::from_slice(line).unwrap()).collect();
+    assert_eq!(diagnostics.len(), 2);
+    for (diagnostic, (code, start, column)) in diagnostics.iter().zip([("E0101", 18, 3), ("W0001", 32, 17)]) {
+        assert_eq!(diagnostic["code"], code);
+        assert_eq!(diagnostic["file"], input);
+        assert_eq!(diagnostic["span"], json!({ "start": start, "end": start + 1 }));
+        assert_eq!((&diagnostic["line"], &diagnostic["column"]), (&json!(2), &json!(column)));
+    }
+    // Errors without a place in the source still name the input
+    let output = kompilator(&["--error-format=json", "/nonexistent/input.imp", "/nonexistent/output.mr"]);
+    let diagnostic: Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert_eq!(diagnostic["code"], "E0202");
+    assert_eq!(diagnostic["file"], "/nonexistent/input.imp");
+    assert_eq!(diagnostic["span"], Value::Null);
+}
+
+#[test]
+fn examples_compile_or_report() {
+    let dir = scratch_dir("examples_compile_or_report");
+    for example in examples() {
+        let source = fs::read_to_string(&example).unwrap();
+        let compiled = compile_source(&dir, &source, &example.display().to_string());
+        // Some of the examples in `errors` only get warnings
+        let is_error_example = example.parent().unwrap().ends_with("errors");
+        assert!(compiled || is_error_example, "{} did not compile", example.display());
+    }
+}
+
+#[test]
+fn truncated_examples() {
+    let dir = scratch_dir("truncated_examples");
+    for example in examples() {
+        let source = fs::read_to_string(&example).unwrap();
+        for (end, _) in source.match_indices(char::is_whitespace) {
+            compile_source(&dir, &source[..end], &format!("{} cut at byte {end}", example.display()));
+        }
+    }
+}
+
+#[test]
+fn semantic_errors() {
+    let dir = scratch_dir("semantic_errors");
+    let cases = [
+        "PROCEDURE p(x) IS IN x := 1; END PROGRAM IS IN p(a); END",
+        "PROCEDURE p(T t) IS IN t[0] := 1; END PROGRAM IS IN p(a); END",
+        "PROGRAM IS i IN i := 0; t[i] := 1; END",
+        "PROGRAM IS i IN READ i; WRITE t[i]; END",
+        "PROGRAM IS t[2] IN t[2] := 1; WRITE t[99999999999999999999999999]; END",
+        "PROGRAM IS t[18446744073709551616] IN WRITE t[0]; END",
+        "PROGRAM IS t[18446744073709551615], a IN a := 1; WRITE a; END",
+        "PROGRAM IS t[9223372036854775808], u[9223372036854775808] IN u[0] := 1; END",
+        "PROCEDURE p(x) IS IN p(x); END PROGRAM IS a IN p(a); END",
+        "PROCEDURE p(x) IS y IN y := 1; p(y); END PROGRAM IS a IN p(a); END",
+        "PROCEDURE p(x) IS IN q(x); END PROCEDURE q(x) IS IN p(x); END PROGRAM IS a IN p(a); q(a); END",
+        "PROCEDURE p(x) IS IN q(x); END PROCEDURE q(x) IS IN x := 1; END PROGRAM IS a IN p(a); END",
+        "PROCEDURE p(x) IS IN x := 1; END PROGRAM IS a IN p(a, a); END",
+        "PROCEDURE p(x) IS IN x := 1; END PROCEDURE p(y) IS IN y := 2; END PROGRAM IS a IN p(a); END",
+        "PROGRAM IS a, a IN a := a / 0; a := a % 0; END",
+    ];
+    for source in cases {
+        assert!(!compile_source(&dir, source, source), "expected an error for {source}");
+    }
+}
+
+#[test]
+fn huge_literals() {
+    let dir = scratch_dir("huge_literals");
+    let literal = "9".repeat(500);
+    let source = format!("PROGRAM IS a IN a := {literal}; a := a * {literal}; WRITE a; END");
+    assert!(compile_source(&dir, &source, "a 500 digit literal"));
+}
+
+#[test]
+fn garbage_input() {
+    let dir = scratch_dir("garbage_input");
+    let cases = ["", " ", "PROGRAM", "END", "PROGRAM IS IN END", "ąę€ PROGRAM", "PROGRAM IS a IN a := ; END", "\0\0\0"];
+    for source in cases {
+        compile_source(&dir, source, &format!("{source:?}"));
+    }
+}
@@ -0,0 +1,198 @@
+use std::fs;
+
+use gembalang::ir::{self, BlockId};
+use gembalang::passes::{PassOptions, PASSES};
+use gembalang::peephole::{PeepholeOptions, RULES};
+use gembalang::{compile, parse, vm, CompileOptions, OptLevel};
+
+/// Examples whose header lists the input after `# ?` and the expected output after `# >`
+const EXAMPLES: [&str; 6] = [
+    "gembala/example2.imp",
+    "gembala/example3.imp",
+    "gembala/example4.imp",
+    "gembala/example5.imp",
+    "gembala/example6.imp",
+    "gembala/example9.imp",
+];
+
+fn header_values<'a>(source: &'a str, prefix: &str) -> Vec<&'a str> {
+    source.lines().filter_map(|line| line.strip_prefix(prefix)).map(str::trim).collect()
+}
+
+#[test]
+fn examples_give_documented_output() {
+    for example in EXAMPLES {
+        let source = fs::read_to_string(format!("{}/examples/{example}", env!("CARGO_MANIFEST_DIR"))).unwrap();
+        let program = compile(&source, &CompileOptions::default()).unwrap();
+        let input = header_values(&source, "# ?").join("\n");
+        let mut output: Vec<u8> = Vec::new();
+        vm::run(&program.assembly, &mut input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.lines().collect::<Vec<&str>>(), header_values(&source, "# >"), "wrong output of {example}");
+    }
+}
+
+#[test]
+fn big_constants() {
+    let source = "PROGRAM IS a IN a := 340282366920938463463374607431768211457; WRITE a; a := a - 2; WRITE a; END";
+    let program = compile(source, &CompileOptions::default()).unwrap();
+    let mut output: Vec<u8> = Vec::new();
+    vm::run(&program.assembly, &mut "".as_bytes(), &mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "340282366920938463463374607431768211457\n340282366920938463463374607431768211455\n");
+}
+
+#[test]
+fn procedures_called_as_subroutines() {
+    let source = "
+        PROCEDURE swap(T t, i, j) IS x IN x := t[i]; t[i] := t[j]; t[j] := x; END
+        PROCEDURE reverse(T t, n) IS i, j IN
+          i := 0; j := n - 1;
+          WHILE i < j DO swap(t, i, j); i := i + 1; j := j - 1; ENDWHILE
+        END
+        PROGRAM IS a[4], b[3], n IN
+          n := 0; REPEAT a[n] := n * 10; n := n + 1; UNTIL n = 4;
+          reverse(a, n); n := 3; b[0] := 7; b[1] := 8; b[2] := 9; reverse(b, n); reverse(a, n);
+          WRITE a[0]; WRITE a[1]; WRITE a[2]; WRITE a[3]; WRITE b[0]; WRITE b[2];
+        END";
+    let mut costs = Vec::new();
+    for opt_level in [OptLevel::Os, OptLevel::O2] {
+        let program = compile(source, &CompileOptions { opt_level, ..Default::default() }).unwrap();
+        if opt_level == OptLevel::Os {
+            assert!(program.assembly.contains("STRK"));
+        }
+        let mut output: Vec<u8> = Vec::new();
+        let execution = vm::run(&program.assembly, &mut "".as_bytes(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "10\n20\n30\n0\n9\n7\n");
+        costs.push(execution.cost);
+    }
+    assert!(costs[1] <= costs[0]);
+}
+
+#[test]
+fn shared_arithmetic_routines() {
+    let source = fs::read_to_string(format!("{}/examples/gembala/example5.imp", env!("CARGO_MANIFEST_DIR"))).unwrap();
+    let input = header_values(&source, "# ?").join("\n");
+    let mut outputs = Vec::new();
+    for opt_level in [OptLevel::O0, OptLevel::Os] {
+        let program = compile(&source, &CompileOptions { opt_level, ..Default::default() }).unwrap();
+        assert_eq!(program.assembly.contains("JUMPR h"), opt_level == OptLevel::Os);
+        let mut output: Vec<u8> = Vec::new();
+        vm::run(&program.assembly, &mut input.as_bytes(), &mut output).unwrap();
+        outputs.push(output);
+    }
+    assert_eq!(outputs[0], outputs[1]);
+}
+
+#[test]
+fn constant_operands() {
+    let expressions = [
+        "x * 0", "x * 1", "x * 8", "10 * x", "x / 1", "x / 4", "x / 0", "0 / x", "x % 8", "x % 1", "x % 0", "x + 0", "0 + x", "x - 0",
+        "0 - x", "7 - 9", "7 % 4",
+    ];
+    let commands: Vec<String> = expressions.iter().map(|expression| format!("y := {expression}; WRITE y;")).collect();
+    let source = format!("PROGRAM IS x, y IN READ x; {} END", commands.join(" "));
+    let mut runs = Vec::new();
+    for opt_level in [OptLevel::O0, OptLevel::O2] {
+        let program = compile(&source, &CompileOptions { opt_level, ..Default::default() }).unwrap();
+        let mut output: Vec<u8> = Vec::new();
+        let execution = vm::run(&program.assembly, &mut "29".as_bytes(), &mut output).unwrap();
+        runs.push((String::from_utf8(output).unwrap(), execution.cost));
+    }
+    assert_eq!(runs[1].0, "0\n29\n232\n290\n29\n7\n0\n0\n5\n0\n0\n29\n29\n29\n0\n0\n3\n");
+    assert_eq!(runs[0].0, runs[1].0);
+    assert!(runs[1].1 < runs[0].1);
+}
+
+#[test]
+fn variables_kept_in_registers() {
+    let source = "
+        PROCEDURE add(s, v) IS IN s := s + v; END
+        PROGRAM IS n, i, s, p, t[5] IN
+          READ n; i := 0; s := 0; p := 1;
+          WHILE i < n DO
+            t[i] := i * i; p := p * n; p := p % 1000; add(s, i); s := s + t[i];
+            i := i + 1;
+          ENDWHILE
+          WRITE s; WRITE p; WRITE i;
+        END";
+    let mut runs = Vec::new();
+    for opt_level in [OptLevel::O0, OptLevel::O2] {
+        let program = compile(source, &CompileOptions { opt_level, ..Default::default() }).unwrap();
+        let mut output: Vec<u8> = Vec::new();
+        let execution = vm::run(&program.assembly, &mut "5".as_bytes(), &mut output).unwrap();
+        runs.push((String::from_utf8(output).unwrap(), execution.cost));
+    }
+    assert_eq!(runs[1].0, "40\n125\n5\n");
+    assert_eq!(runs[0].0, runs[1].0);
+    assert!(runs[1].1 < runs[0].1);
+}
+
+#[test]
+fn peephole_rules_keep_behaviour() {
+    let source = fs::read_to_string(format!("{}/examples/gembala/example4.imp", env!("CARGO_MANIFEST_DIR"))).unwrap();
+    let input = header_values(&source, "# ?").join("\n");
+    let run = |peephole: PeepholeOptions| {
+        let program = compile(&source, &CompileOptions { peephole, ..Default::default() }).unwrap();
+        let mut output: Vec<u8> = Vec::new();
+        vm::run(&program.assembly, &mut input.as_bytes(), &mut output).unwrap();
+        (String::from_utf8(output).unwrap(), program.assembly.lines().count())
+    };
+    let (output, length) = run(PeepholeOptions::new());
+    assert_eq!(output.lines().collect::<Vec<&str>>(), header_values(&source, "# >"));
+    let mut all_disabled = PeepholeOptions::new();
+    for rule in RULES {
+        let mut disabled = PeepholeOptions::new();
+        disabled.disable(rule.name).unwrap();
+        all_disabled.disable(rule.name).unwrap();
+        let (rule_output, rule_length) = run(disabled);
+        assert_eq!(rule_output, output, "{} changes the output", rule.name);
+        assert!(rule_length >= length);
+    }
+    let (unoptimised_output, unoptimised_length) = run(all_disabled);
+    assert_eq!(unoptimised_output, output);
+    assert!(unoptimised_length > length);
+}
+
+#[test]
+fn passes_keep_behaviour() {
+    let source = fs::read_to_string(format!("{}/examples/gembala/example5.imp", env!("CARGO_MANIFEST_DIR"))).unwrap();
+    let input = header_values(&source, "# ?").join("\n");
+    for opt_level in [OptLevel::O1, OptLevel::Os] {
+        for pass in PASSES {
+            let mut passes = PassOptions::new();
+            passes.disable(pass.name()).unwrap();
+            let program = compile(&source, &CompileOptions { opt_level, passes, ..Default::default() }).unwrap();
+            let mut output: Vec<u8> = Vec::new();
+            vm::run(&program.assembly, &mut input.as_bytes(), &mut output).unwrap();
+            let output = String::from_utf8(output).unwrap();
+            assert_eq!(output.lines().collect::<Vec<&str>>(), header_values(&source, "# >"), "{} changes the output", pass.name());
+        }
+    }
+}
+
+#[test]
+fn lowered_through_ir() {
+    // With `-O0` the code is built from the IR as it was lowered, with the procedures copied into their calls
+    for example in EXAMPLES {
+        let source = fs::read_to_string(format!("{}/examples/{example}", env!("CARGO_MANIFEST_DIR"))).unwrap();
+        let program = compile(&source, &CompileOptions { opt_level: OptLevel::O0, ..Default::default() }).unwrap();
+        let input = header_values(&source, "# ?").join("\n");
+        let mut output: Vec<u8> = Vec::new();
+        vm::run(&program.assembly, &mut input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.lines().collect::<Vec<&str>>(), header_values(&source, "# >"), "wrong output of {example} through the IR");
+    }
+    let source = "PROGRAM IS n IN READ n; WHILE n > 0 DO IF n = 3 THEN WRITE n; ENDIF n := n - 1; ENDWHILE END";
+    let main = ir::lower(&parse(source).unwrap()).0.unwrap().functions.remove(0);
+    let predecessors = main.predecessors();
+    assert_eq!(main.blocks.len(), 6);
+    // The loop header is reached from the entry and from the end of the body
+    assert_eq!(main.successors(BlockId(0)), vec![BlockId(1)]);
+    assert_eq!(predecessors[1], vec![BlockId(0), BlockId(4)]);
+    assert_eq!(predecessors[4], vec![BlockId(2), BlockId(3)]);
+    let program = compile(source, &CompileOptions { opt_level: OptLevel::O0, ..Default::default() }).unwrap();
+    assert_eq!(program.ir.functions[0].blocks, main.blocks);
+    let mut output: Vec<u8> = Vec::new();
+    vm::run(&program.assembly, &mut "4".as_bytes(), &mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap().lines().next(), Some("3"));
+}
@@ -0,0 +1,32 @@
+use gembalang::parse;
+use gembalang::symbols::{SymbolIndex, SymbolKind};
+
+const SOURCE: &str = "PROCEDURE p(T t, n) IS\n  x\nIN\n  x := n;\n  t[x] := n;\nEND\n\nPROGRAM IS\n  a[10], b\nIN\n  READ b;\n  p(a, b);\nEND\n";
+
+fn index() -> SymbolIndex {
+    SymbolIndex::new(&parse(SOURCE).unwrap())
+}
+
+#[test]
+fn resolves_names_to_their_declarations() {
+    let index = index();
+    let call = SOURCE.find("p(a").unwrap();
+    let procedure = index.symbol_at(call).unwrap();
+    assert_eq!(procedure.span.start, SOURCE.find("p(T").unwrap());
+    assert_eq!(procedure.to_string(), "procedure `p(T t, n)`");
+    let t = index.symbol_at(SOURCE.find("t[x]").unwrap()).unwrap();
+    assert_eq!(t.kind, SymbolKind::ArrayParameter);
+    let a = index.symbol_at(SOURCE.find("a, b);").unwrap()).unwrap();
+    assert_eq!(a.to_string(), "array `a[10]`");
+    let x = index.symbol_at(SOURCE.find("x\n").unwrap()).unwrap();
+    assert_eq!(x.references.len(), 2);
+}
+
+#[test]
+fn lists_the_names_visible_in_a_scope() {
+    let index = index();
+    let names = |offset: usize| -> Vec<String> { index.visible_at(offset).iter().map(|symbol| symbol.name.clone()).collect() };
+    assert_eq!(names(SOURCE.find("x := n").unwrap()), ["t", "n", "x"]);
+    assert_eq!(names(SOURCE.find("READ").unwrap()), ["p", "a", "b"]);
+    assert_eq!(index.procedures().count(), 1);
+}